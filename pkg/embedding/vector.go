@@ -1,6 +1,9 @@
 package embedding
 
-import "math"
+import (
+	"math"
+	"sort"
+)
 
 // CosineSimilarity computes cosine similarity between two vectors.
 func CosineSimilarity(a, b []float32) float32 {
@@ -22,6 +25,37 @@ func CosineSimilarity(a, b []float32) float32 {
 	return dotProduct / (sqrt32(normA) * sqrt32(normB))
 }
 
+// DimensionContribution is one dimension's share of a similarity score.
+type DimensionContribution struct {
+	Dimension    int
+	Contribution float32
+}
+
+// TopContributingDimensions returns the k dimensions whose element-wise
+// products contribute most to the cosine similarity of a and b, largest
+// first. Contributions are taken over the normalized vectors, so summed over
+// every dimension they equal CosineSimilarity(a, b). k <= 0 or k larger than
+// the dimension returns all of them.
+func TopContributingDimensions(a, b []float32, k int) []DimensionContribution {
+	if len(a) != len(b) || len(a) == 0 {
+		return nil
+	}
+
+	na, nb := Normalize(a), Normalize(b)
+	contributions := make([]DimensionContribution, len(a))
+	for i := range na {
+		contributions[i] = DimensionContribution{Dimension: i, Contribution: na[i] * nb[i]}
+	}
+	sort.SliceStable(contributions, func(i, j int) bool {
+		return contributions[i].Contribution > contributions[j].Contribution
+	})
+
+	if k > 0 && k < len(contributions) {
+		contributions = contributions[:k]
+	}
+	return contributions
+}
+
 // sqrt32 computes square root for float32.
 func sqrt32(x float32) float32 {
 	return float32(math.Sqrt(float64(x)))
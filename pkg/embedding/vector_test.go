@@ -0,0 +1,36 @@
+package embedding
+
+import (
+	"math"
+	"testing"
+)
+
+func TestTopContributingDimensions(t *testing.T) {
+	a := []float32{0.9, -0.2, 0.4, 0.1}
+	b := []float32{0.8, 0.3, 0.5, -0.6}
+
+	all := TopContributingDimensions(a, b, 0)
+	if len(all) != len(a) {
+		t.Fatalf("Expected %d contributions, got %d", len(a), len(all))
+	}
+
+	var sum float32
+	for i, c := range all {
+		sum += c.Contribution
+		if i > 0 && c.Contribution > all[i-1].Contribution {
+			t.Errorf("Contributions not sorted descending at %d: %v", i, all)
+		}
+	}
+	if cos := CosineSimilarity(a, b); math.Abs(float64(sum-cos)) > 1e-5 {
+		t.Errorf("Contributions sum to %f, want cosine %f", sum, cos)
+	}
+
+	top := TopContributingDimensions(a, b, 2)
+	if len(top) != 2 || top[0].Dimension != 0 || top[1].Dimension != 2 {
+		t.Errorf("Expected dimensions [0 2] first, got %v", top)
+	}
+
+	if got := TopContributingDimensions(a, b[:2], 2); got != nil {
+		t.Errorf("Mismatched lengths should return nil, got %v", got)
+	}
+}
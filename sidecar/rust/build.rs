@@ -1,7 +1,27 @@
+/// Version of `package` as resolved in Cargo.lock, or "unknown".
+fn locked_version(lock: &str, package: &str) -> String {
+    let name_line = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line == name_line {
+            if let Some(version) = lines.next().and_then(|l| l.strip_prefix("version = ")) {
+                return version.trim_matches('"').to_string();
+            }
+        }
+    }
+    "unknown".to_string()
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(true)
         .build_client(true)
-        .compile_protos(&["../../pkg/embedding/pb/sidecar.proto"], &["../../pkg/embedding/sidecar/pb"])?;
+        .compile_protos(&["../../pkg/embedding/pb/sidecar.proto"], &["../../pkg/embedding/pb"])?;
+
+    // Reported in EmbedResponse.provenance.
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rustc-env=SIDECAR_CANDLE_VERSION={}", locked_version(&lock, "candle-core"));
+    println!("cargo:rustc-env=SIDECAR_TOKENIZERS_VERSION={}", locked_version(&lock, "tokenizers"));
     Ok(())
 }
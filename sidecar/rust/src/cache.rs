@@ -0,0 +1,92 @@
+//! LRU cache of Embed results, sized by `InitRequest.embed_cache_size`.
+//!
+//! The cache belongs to the loaded model, so a reload or unload empties it.
+//! Entries hold the model's pooled output for the exact text it was given
+//! (after any prefix, padding and sanitizing), before per-request
+//! post-processing such as clipping and normalization. Those run on every
+//! hit, so requests that differ only in them share an entry.
+//!
+//! Hits and misses are counted here; the hit rate is logged every
+//! `REPORT_EVERY` lookups.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+const REPORT_EVERY: u64 = 1000;
+
+pub struct LruCache<V> {
+    capacity: usize,
+    inner: Mutex<Inner<V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner<V> {
+    /// Value and last-use tick per key.
+    entries: HashMap<String, (V, u64)>,
+    /// Keys by last-use tick; the first entry is the least recently used.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl<V: Clone> LruCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *inner;
+        inner.tick += 1;
+        let value = match inner.entries.get_mut(key) {
+            Some((value, last_used)) => {
+                inner.recency.remove(last_used);
+                *last_used = inner.tick;
+                inner.recency.insert(inner.tick, key.to_string());
+                Some(value.clone())
+            }
+            None => None,
+        };
+        self.record(value.is_some(), inner.entries.len());
+        value
+    }
+
+    pub fn insert(&self, key: String, value: V) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *inner;
+        inner.tick += 1;
+        if let Some((_, last_used)) = inner.entries.insert(key.clone(), (value, inner.tick)) {
+            inner.recency.remove(&last_used);
+        }
+        inner.recency.insert(inner.tick, key);
+        while inner.entries.len() > self.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else { break };
+            inner.entries.remove(&oldest);
+        }
+    }
+
+    fn record(&self, hit: bool, entries: usize) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let (hits, misses) = (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed));
+        if (hits + misses) % REPORT_EVERY == 0 {
+            tracing::info!(
+                "Embed cache hit rate {:.1}% over {} lookups ({} of {} entries used)",
+                100.0 * hits as f64 / (hits + misses) as f64,
+                hits + misses,
+                entries,
+                self.capacity
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Coalescing of identical concurrent requests.
+//!
+//! With `SIDECAR_COALESCE_EMBEDS=1`, an Embed that arrives while an identical
+//! one (same request message, whatever its deadline) is still running waits
+//! for that call and shares its result instead of running its own forward
+//! pass. Only concurrent calls are merged: once the shared call finishes, the
+//! next identical request runs afresh. Results that belong to the caller
+//! rather than the request, such as its deadline expiring, are never shared:
+//! the caller gets its own result and one of the waiters runs the work
+//! itself. The same happens when the call doing the work is cancelled (its
+//! client went away).
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::OnceCell;
+
+pub struct Coalescer<T> {
+    inflight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    /// Run `work` unless a call with the same `key` is already running, in
+    /// which case wait for and return a copy of its result. A result for
+    /// which `shareable` is false goes back to its own caller only, and a
+    /// waiting call then runs its own `work`.
+    pub async fn run(&self, key: String, work: impl Future<Output = T>, shareable: impl FnOnce(&T) -> bool) -> T {
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let shared = cell
+            .get_or_try_init(|| async {
+                let value = work.await;
+                if shareable(&value) {
+                    Ok(value)
+                } else {
+                    Err(value)
+                }
+            })
+            .await;
+        let value = match shared {
+            Ok(value) => value.clone(),
+            Err(own) => own,
+        };
+
+        let mut inflight = self.inflight.lock().unwrap_or_else(PoisonError::into_inner);
+        if inflight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+            inflight.remove(&key);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Start `n` identical calls at once; each call's work counts itself in
+    /// `runs`, takes 50 ms so the calls overlap, and returns `result(run)`
+    /// for the run number it got.
+    async fn concurrent(
+        n: usize,
+        runs: &Arc<AtomicUsize>,
+        result: fn(usize) -> Result<usize, &'static str>,
+    ) -> Vec<Result<usize, &'static str>> {
+        let coalescer = Arc::new(Coalescer::default());
+        let calls: Vec<_> = (0..n)
+            .map(|_| {
+                let (coalescer, runs) = (coalescer.clone(), runs.clone());
+                tokio::spawn(async move {
+                    let work = async move {
+                        let run = runs.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        result(run)
+                    };
+                    coalescer.run("key".to_string(), work, |result| result.is_ok()).await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for call in calls {
+            results.push(call.await.unwrap());
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_calls_run_once() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let results = concurrent(8, &runs, Ok).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|result| *result == Ok(0)));
+    }
+
+    #[tokio::test]
+    async fn unshareable_result_stays_with_its_caller() {
+        // The first run fails the way a deadline does; a waiter then reruns
+        // and its result is shared with the rest.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let results = concurrent(4, &runs, |run| if run == 0 { Err("deadline") } else { Ok(run) }).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert_eq!(results.iter().filter(|result| **result == Ok(1)).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn finished_calls_are_not_reused() {
+        let coalescer = Coalescer::default();
+        let runs = AtomicUsize::new(0);
+        for expected in 0..2 {
+            let run = coalescer
+                .run("key".to_string(), async { runs.fetch_add(1, Ordering::SeqCst) }, |_| true)
+                .await;
+            assert_eq!(run, expected);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Connection limits for the gRPC listener.
+//!
+//! tonic's own `serve(addr)` accepts every connection and keeps it for as long
+//! as the peer likes, so many idle or deliberately slow clients can pin file
+//! descriptors and memory. `serve_limited` accepts connections itself and
+//! applies the limits below. All are read from the environment:
+//!
+//! | variable                              | default  | effect                                               |
+//! |---------------------------------------|----------|------------------------------------------------------|
+//! | `SIDECAR_MAX_CONNECTIONS`             | 1024     | connections beyond this are closed right after accept |
+//! | `SIDECAR_MAX_STREAMS_PER_CONNECTION`  | 32       | concurrent requests served per connection            |
+//! | `SIDECAR_REQUEST_TIMEOUT_MS`          | disabled | whole-request deadline, including reading the body   |
+//! | `SIDECAR_CONNECTION_IDLE_TIMEOUT_MS`  | 300000   | close a connection that sends nothing for this long  |
+//! | `SIDECAR_HTTP2_KEEPALIVE_MS`          | 60000    | interval of server HTTP/2 pings                      |
+//!
+//! The idle timeout is the slow-loris defence: a peer that stalls in the
+//! HTTP/2 handshake or mid-frame stops producing bytes and is dropped. Healthy
+//! idle clients keep their connection because they answer the keepalive pings,
+//! which count as reads; keep the keepalive interval below the idle timeout.
+//! A value of 0 disables the idle timeout or keepalive.
+//!
+//! To check the connection limit by hand, start the sidecar with
+//! `SIDECAR_MAX_CONNECTIONS=2`, hold two connections open (e.g. two
+//! `nc localhost 50051` sessions), then run `grpcurl -plaintext localhost:50051
+//! sidecar.LLMService/Health`: it fails with a connection reset and the sidecar
+//! logs "Connection limit reached". Closing one `nc` lets the next call through.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, Router, TcpConnectInfo};
+
+use crate::env_millis;
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_MAX_STREAMS_PER_CONNECTION: usize = 32;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_HTTP2_KEEPALIVE: Duration = Duration::from_secs(60);
+
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub max_streams_per_connection: usize,
+    pub request_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub http2_keepalive: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    pub fn from_env() -> Self {
+        let count = |name: &str, default: usize| match std::env::var(name) {
+            Ok(value) => value.parse().unwrap_or_else(|_| {
+                tracing::warn!("Ignoring {}={:?}: not a count", name, value);
+                default
+            }),
+            Err(_) => default,
+        };
+        // env_millis treats 0 as unset; here unset means the default and 0 means off.
+        let duration = |name: &str, default: Duration| match std::env::var(name).as_deref() {
+            Ok("0") => None,
+            Ok(_) => env_millis(name).or(Some(default)),
+            Err(_) => Some(default),
+        };
+        Self {
+            max_connections: count("SIDECAR_MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS).max(1),
+            max_streams_per_connection: count("SIDECAR_MAX_STREAMS_PER_CONNECTION", DEFAULT_MAX_STREAMS_PER_CONNECTION)
+                .max(1),
+            request_timeout: env_millis("SIDECAR_REQUEST_TIMEOUT_MS"),
+            idle_timeout: duration("SIDECAR_CONNECTION_IDLE_TIMEOUT_MS", DEFAULT_IDLE_TIMEOUT),
+            http2_keepalive: duration("SIDECAR_HTTP2_KEEPALIVE_MS", DEFAULT_HTTP2_KEEPALIVE),
+        }
+    }
+}
+
+/// An accepted connection holding one slot of the connection limit until it
+/// is dropped, and closing itself after `idle_timeout` without reads.
+pub struct LimitedStream {
+    inner: TcpStream,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Connected for LimitedStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.inner.connect_info()
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if let Some((timeout, sleep)) = &mut this.idle {
+                    sleep.as_mut().reset(Instant::now() + *timeout);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if let Some((timeout, sleep)) = &mut this.idle {
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("connection idle for {:?}", timeout),
+                        )));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Accept connections on `addr` under `limits`, handing the admitted ones to
+/// `router`. The router should already carry the per-connection settings from
+/// `limits` (see `main`). Once `shutdown` completes no new requests are taken,
+/// and this returns when the in-flight ones have finished.
+pub async fn serve_limited(
+    router: Router,
+    addr: SocketAddr,
+    limits: &ConnectionLimits,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let idle_timeout = limits.idle_timeout;
+    let (tx, rx) = mpsc::channel::<std::io::Result<LimitedStream>>(1);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually EMFILE; back off instead of spinning on the error.
+                    tracing::warn!("Accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = slots.clone().try_acquire_owned() else {
+                tracing::warn!("Connection limit reached; closing connection from {}", peer);
+                continue;
+            };
+            let _ = stream.set_nodelay(true);
+            let idle = idle_timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+            let limited = LimitedStream {
+                inner: stream,
+                idle,
+                _permit: permit,
+            };
+            if tx.send(Ok(limited)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    router.serve_with_incoming_shutdown(ReceiverStream::new(rx), shutdown).await?;
+    Ok(())
+}
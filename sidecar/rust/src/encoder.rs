@@ -0,0 +1,228 @@
+//! Encoder architectures, selected by `model_type` in config.json.
+//!
+//! Each architecture registers how to read its dimensions from config.json
+//! and how to build its candle model. Pooling, projection and the rest of the
+//! embedding pipeline only see the `Embed` trait, so adding an architecture
+//! means implementing `Embed` for it and adding a `REGISTRY` entry.
+
+use candle_core::Tensor;
+use candle_nn::VarBuilder;
+use candle_transformers::models::{bert, distilbert};
+
+/// An encoder producing per-token hidden states.
+pub trait Embed: Send {
+    /// Hidden states `[batch, seq, hidden]` for `input_ids` under a binary
+    /// `attention_mask` (1 = attend, 0 = padding), both `[batch, seq]`.
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> candle_core::Result<Tensor>;
+
+    /// Like `forward` for a sentence pair, with `token_type_ids` marking each
+    /// token's segment. Architectures without segment embeddings ignore them.
+    fn forward_pair(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> candle_core::Result<Tensor> {
+        let _ = token_type_ids;
+        self.forward(input_ids, attention_mask)
+    }
+}
+
+/// The config.json values the pipeline needs, whatever the architecture
+/// calls them.
+pub struct EncoderConfig {
+    pub hidden_size: usize,
+    pub vocab_size: usize,
+    pub max_position_embeddings: usize,
+    pub num_hidden_layers: usize,
+}
+
+pub struct Architecture {
+    pub model_type: &'static str,
+    pub config: fn(&serde_json::Value) -> anyhow::Result<EncoderConfig>,
+    pub load: fn(&serde_json::Value, VarBuilder) -> anyhow::Result<Box<dyn Embed>>,
+}
+
+const REGISTRY: &[Architecture] = &[
+    Architecture {
+        model_type: "bert",
+        config: bert_config,
+        load: load_bert,
+    },
+    Architecture {
+        model_type: "distilbert",
+        config: distilbert_config,
+        load: load_distilbert,
+    },
+];
+
+/// The architecture for `config`. Configs without a `model_type` are treated
+/// as BERT, which is what every model loaded before the registry was.
+pub fn architecture(config: &serde_json::Value) -> anyhow::Result<&'static Architecture> {
+    let model_type = config.get("model_type").and_then(|v| v.as_str()).unwrap_or("bert");
+    REGISTRY.iter().find(|arch| arch.model_type == model_type).ok_or_else(|| {
+        let supported: Vec<_> = REGISTRY.iter().map(|arch| arch.model_type).collect();
+        anyhow::anyhow!("Unsupported model_type '{}' (supported: {})", model_type, supported.join(", "))
+    })
+}
+
+fn usize_field(config: &serde_json::Value, name: &str) -> anyhow::Result<usize> {
+    config
+        .get(name)
+        .and_then(|v| v.as_u64())
+        .map(|v| v as usize)
+        .ok_or_else(|| anyhow::anyhow!("config.json is missing \"{}\"", name))
+}
+
+impl Embed for bert::BertModel {
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+        // Single-segment input: every token belongs to segment 0.
+        let token_type_ids = input_ids.zeros_like()?;
+        bert::BertModel::forward(self, input_ids, &token_type_ids, Some(attention_mask))
+    }
+
+    fn forward_pair(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> candle_core::Result<Tensor> {
+        bert::BertModel::forward(self, input_ids, token_type_ids, Some(attention_mask))
+    }
+}
+
+fn bert_config(config: &serde_json::Value) -> anyhow::Result<EncoderConfig> {
+    Ok(EncoderConfig {
+        hidden_size: usize_field(config, "hidden_size")?,
+        vocab_size: usize_field(config, "vocab_size")?,
+        max_position_embeddings: usize_field(config, "max_position_embeddings")?,
+        num_hidden_layers: usize_field(config, "num_hidden_layers")?,
+    })
+}
+
+fn load_bert(config: &serde_json::Value, vb: VarBuilder) -> anyhow::Result<Box<dyn Embed>> {
+    let config: bert::Config = serde_json::from_value(config.clone())?;
+    Ok(Box::new(bert::BertModel::load(vb, &config)?))
+}
+
+impl Embed for distilbert::DistilBertModel {
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+        // DistilBERT masks positions where its mask is *set*, broadcast over
+        // heads and query positions: invert to [batch, 1, 1, seq].
+        let (batch, seq) = attention_mask.dims2()?;
+        let masked = attention_mask.eq(0u8)?.reshape((batch, 1, 1, seq))?;
+        distilbert::DistilBertModel::forward(self, input_ids, &masked)
+    }
+}
+
+fn distilbert_config(config: &serde_json::Value) -> anyhow::Result<EncoderConfig> {
+    Ok(EncoderConfig {
+        hidden_size: usize_field(config, "dim")?,
+        vocab_size: usize_field(config, "vocab_size")?,
+        max_position_embeddings: usize_field(config, "max_position_embeddings")?,
+        num_hidden_layers: usize_field(config, "n_layers")?,
+    })
+}
+
+fn load_distilbert(config: &serde_json::Value, vb: VarBuilder) -> anyhow::Result<Box<dyn Embed>> {
+    let config: distilbert::Config = serde_json::from_value(config.clone())?;
+    Ok(Box::new(distilbert::DistilBertModel::load(vb, &config)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device, IndexOp};
+    use serde_json::json;
+
+    fn bert_json() -> serde_json::Value {
+        json!({
+            "model_type": "bert", "vocab_size": 8, "hidden_size": 8, "num_hidden_layers": 1,
+            "num_attention_heads": 2, "intermediate_size": 16, "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0, "max_position_embeddings": 16, "type_vocab_size": 2,
+            "initializer_range": 0.02, "layer_norm_eps": 1e-12, "pad_token_id": 0
+        })
+    }
+
+    fn distilbert_json() -> serde_json::Value {
+        json!({
+            "model_type": "distilbert", "vocab_size": 8, "dim": 8, "n_layers": 1, "n_heads": 2,
+            "hidden_dim": 16, "activation": "gelu", "max_position_embeddings": 16,
+            "initializer_range": 0.02, "pad_token_id": 0
+        })
+    }
+
+    #[test]
+    fn missing_model_type_means_bert() {
+        let mut config = bert_json();
+        config.as_object_mut().unwrap().remove("model_type");
+        assert_eq!(architecture(&config).unwrap().model_type, "bert");
+    }
+
+    #[test]
+    fn model_type_selects_the_architecture() {
+        assert_eq!(architecture(&distilbert_json()).unwrap().model_type, "distilbert");
+    }
+
+    #[test]
+    fn unknown_model_type_lists_the_supported_ones() {
+        let error = architecture(&json!({ "model_type": "roberta" })).err().unwrap().to_string();
+        assert_eq!(error, "Unsupported model_type 'roberta' (supported: bert, distilbert)");
+    }
+
+    #[test]
+    fn distilbert_config_reads_its_own_field_names() {
+        let config = (architecture(&distilbert_json()).unwrap().config)(&distilbert_json()).unwrap();
+        assert_eq!(config.hidden_size, 8);
+        assert_eq!(config.num_hidden_layers, 1);
+        assert_eq!(config.vocab_size, 8);
+        assert_eq!(config.max_position_embeddings, 16);
+    }
+
+    #[test]
+    fn missing_config_field_is_named() {
+        let mut config = bert_json();
+        config.as_object_mut().unwrap().remove("hidden_size");
+        let error = bert_config(&config).err().unwrap().to_string();
+        assert_eq!(error, "config.json is missing \"hidden_size\"");
+    }
+
+    /// Hidden states of the real tokens must not change when padding is
+    /// appended under a zero mask; an inverted mask would attend to the
+    /// padding instead.
+    fn assert_padding_is_masked(config: serde_json::Value) {
+        let device = Device::Cpu;
+        let varmap = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let model = (architecture(&config).unwrap().load)(&config, vb).unwrap();
+
+        let ids = Tensor::new(&[[1i64, 4, 5, 2]], &device).unwrap();
+        let mask = Tensor::new(&[[1u8, 1, 1, 1]], &device).unwrap();
+        let padded_ids = Tensor::new(&[[1i64, 4, 5, 2, 0, 0]], &device).unwrap();
+        let padded_mask = Tensor::new(&[[1u8, 1, 1, 1, 0, 0]], &device).unwrap();
+
+        let hidden = model.forward(&ids, &mask).unwrap();
+        let padded = model.forward(&padded_ids, &padded_mask).unwrap();
+        let difference = (hidden - padded.i((.., 0..4, ..)).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(difference < 1e-4, "padding changed the hidden states by {}", difference);
+    }
+
+    #[test]
+    fn bert_ignores_masked_padding() {
+        assert_padding_is_masked(bert_json());
+    }
+
+    #[test]
+    fn distilbert_ignores_masked_padding() {
+        assert_padding_is_masked(distilbert_json());
+    }
+}
@@ -1,12 +1,17 @@
+// tonic::Status is the error type throughout the service and is large by design.
+#![allow(clippy::result_large_err)]
+
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::RwLock;
 use tonic::{transport::Server, Request, Response, Status};
 
 use candle_core::{Device, Tensor, DType};
-use candle_transformers::models::bert::{BertModel, Config as BertConfig};
-use candle_nn::VarBuilder;
-use tokenizers::Tokenizer;
-use hf_hub::{api::sync::Api, Repo, RepoType};
+use candle_nn::{Linear, Module, VarBuilder};
+use tokenizers::normalizers::{NormalizerWrapper, Sequence};
+use tokenizers::{Encoding, Tokenizer, TruncationParams};
+use hf_hub::api::sync::{ApiBuilder, ApiError, ApiRepo};
+use hf_hub::{Cache, Repo, RepoType};
 
 // Generated proto code
 pub mod sidecar {
@@ -15,302 +20,5916 @@ pub mod sidecar {
 
 use sidecar::{llm_service_server::{LlmService, LlmServiceServer}, *};
 
-// Real embedding model using candle
-struct EmbeddingModel {
-    model: Option<BertModel>,
-    tokenizer: Option<Tokenizer>,
-    device: Device,
-    model_path: String,
-    embedding_dim: usize,
+mod cache;
+mod coalesce;
+mod connection;
+mod encoder;
+mod metrics;
+#[cfg(feature = "qdrant")]
+mod qdrant;
+#[cfg(feature = "shm")]
+mod shm;
+mod signing;
+mod timing;
+#[cfg(feature = "upstream")]
+mod upstream;
+
+/// Input the model cannot meaningfully embed. Surfaced to clients as
+/// `invalid_argument` rather than `internal`.
+#[derive(Debug)]
+struct InvalidInput(String);
+
+impl std::fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-impl EmbeddingModel {
-    fn new() -> Self {
-        Self {
-            model: None,
-            tokenizer: None,
-            device: Device::Cpu,
-            model_path: String::new(),
-            embedding_dim: 384,
-        }
+impl std::error::Error for InvalidInput {}
+
+/// Map an embedding failure to the gRPC status clients should see.
+fn embed_error_status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<InvalidInput>() {
+        Some(invalid) => Status::invalid_argument(invalid.0.clone()),
+        None => Status::internal(format!("Embedding error: {}", e)),
     }
+}
 
-    fn load(&mut self, model_path: &str) -> anyhow::Result<()> {
-        tracing::info!("Loading embedding model from: {}", model_path);
+/// What to do with text that was mangled before it reached us. Prost already
+/// rejects invalid UTF-8 while decoding, so the damage that gets through is
+/// upstream lossy decoding: U+FFFD replacement characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum InvalidTextPolicy {
+    #[default]
+    Allow,
+    Reject,
+    Sanitize,
+}
 
-        // Check if path is a HuggingFace model ID or local path
-        let (tokenizer, config_filename, weights_filename) = if model_path.contains('/') {
-            // HuggingFace model ID
-            tracing::info!("Downloading model from HuggingFace: {}", model_path);
-            let api = Api::new()?;
-            let repo = Repo::with_revision(model_path.to_string(), RepoType::Model, "main".to_string());
-            let api = api.repo(repo);
+impl InvalidTextPolicy {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "" | "allow" => Ok(Self::Allow),
+            "reject" => Ok(Self::Reject),
+            "sanitize" => Ok(Self::Sanitize),
+            other => anyhow::bail!("Unknown invalid_text_policy '{}' (expected allow, reject or sanitize)", other),
+        }
+    }
 
-            let tokenizer_path = api.get("tokenizer.json")?;
-            let config_path = api.get("config.json")?;
-            let model_path = api.get("model.safetensors")?;
+    /// Apply the policy to `text` in place; returns whether it was changed.
+    fn apply(self, text: &mut String) -> Result<bool, Status> {
+        if self == Self::Allow || !text.contains(char::REPLACEMENT_CHARACTER) {
+            return Ok(false);
+        }
+        if self == Self::Reject {
+            let at = text.chars().position(|c| c == char::REPLACEMENT_CHARACTER).unwrap_or(0);
+            return Err(Status::invalid_argument(format!(
+                "text contains a U+FFFD replacement character at char {}",
+                at
+            )));
+        }
+        *text = text.replace(char::REPLACEMENT_CHARACTER, "");
+        Ok(true)
+    }
+}
 
-            let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(|e| anyhow::anyhow!("{}", e))?;
-            (tokenizer, config_path.to_string_lossy().to_string(), model_path.to_string_lossy().to_string())
-        } else {
-            // Local path
-            tracing::info!("Loading model from local path: {}", model_path);
-            let base_path = std::path::Path::new(model_path);
-            let tokenizer_path = base_path.join("tokenizer.json");
-            let config_path = base_path.join("config.json");
-            let model_path = base_path.join("model.safetensors");
+/// What Embed does with input longer than the model context.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum TruncationPolicy {
+    Error,
+    #[default]
+    Flag,
+    Silent,
+}
 
-            if !tokenizer_path.exists() {
-                anyhow::bail!("tokenizer.json not found in {}", base_path.display());
-            }
-            if !config_path.exists() {
-                anyhow::bail!("config.json not found in {}", base_path.display());
-            }
-            if !model_path.exists() {
-                anyhow::bail!("model.safetensors not found in {}", base_path.display());
-            }
+impl TruncationPolicy {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "" | "flag" => Ok(Self::Flag),
+            "silent" => Ok(Self::Silent),
+            _ => anyhow::bail!("Unknown truncation_policy '{}' (expected error, flag or silent)", name),
+        }
+    }
+}
 
-            let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|e| anyhow::anyhow!("{}", e))?;
-            (tokenizer, config_path.to_string_lossy().to_string(), model_path.to_string_lossy().to_string())
-        };
+/// What pooling does when the encoder's output length differs from its input,
+/// leaving the attention mask misaligned with the hidden states.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum SequenceMismatch {
+    #[default]
+    Error,
+    PoolAll,
+}
 
-        // Load config
-        let config = std::fs::read_to_string(&config_filename)?;
-        let config: BertConfig = serde_json::from_str(&config)?;
-        self.embedding_dim = config.hidden_size;
+impl SequenceMismatch {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "" | "error" => Ok(Self::Error),
+            "pool_all" => Ok(Self::PoolAll),
+            other => anyhow::bail!("Unknown sequence_mismatch '{}' (expected error or pool_all)", other),
+        }
+    }
+}
 
-        tracing::info!("Model config: hidden_size={}, num_layers={}", config.hidden_size, config.num_hidden_layers);
+/// How safetensors weights get into memory. `Mmap` maps the files and lets the
+/// OS page them in (and out, under memory pressure); `Buffered` reads them
+/// fully into RAM up front.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum WeightsLoading {
+    #[default]
+    Mmap,
+    Buffered,
+}
 
-        // Load model
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(&[&weights_filename], DType::F32, &self.device)?
-        };
-        let model = BertModel::load(vb, &config)?;
+impl WeightsLoading {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "mmap" => Ok(Self::Mmap),
+            "buffered" => Ok(Self::Buffered),
+            _ => anyhow::bail!("Unknown weights_loading '{}' (expected mmap or buffered)", name),
+        }
+    }
+}
 
-        self.model = Some(model);
-        self.tokenizer = Some(tokenizer);
-        self.model_path = model_path.to_string();
+/// How InitModel replaces the loaded model. Either way the new model is built
+/// on its own and installed only once complete, so no request ever sees a
+/// half-loaded one, and a failed load leaves the previous model as it was
+/// (under `Swap`) or none at all (under `Replace`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum ReloadMode {
+    /// Load beside the current model, which keeps serving until the swap.
+    #[default]
+    Swap,
+    /// Drop the current model and load under the write lock: requests wait,
+    /// but only one model is resident at a time.
+    Replace,
+}
 
-        tracing::info!("Embedding model loaded successfully");
-        Ok(())
+impl ReloadMode {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "swap" => Ok(Self::Swap),
+            "replace" => Ok(Self::Replace),
+            _ => anyhow::bail!("Unknown reload_mode '{}' (expected swap or replace)", name),
+        }
     }
+}
 
-    fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
-        let model = self.model.as_ref().ok_or(anyhow::anyhow!("Model not loaded"))?;
-        let tokenizer = self.tokenizer.as_ref().ok_or(anyhow::anyhow!("Tokenizer not loaded"))?;
+/// How per-token hidden states are reduced to one vector. Mean and max work
+/// over the positions `EmbeddingModel::pools` selects; CLS takes position 0
+/// as is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Pooling {
+    #[default]
+    Mean,
+    Cls,
+    Max,
+}
 
-        // Tokenize input
-        let tokens = tokenizer
-            .encode(text, true)
-            .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+impl Pooling {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "" | "mean" => Ok(Self::Mean),
+            "cls" => Ok(Self::Cls),
+            "max" => Ok(Self::Max),
+            other => anyhow::bail!("Unknown pooling '{}' (expected mean, cls or max)", other),
+        }
+    }
 
-        let input_ids = Tensor::new(
-            tokens.get_ids().iter().map(|&i| i as i64).collect::<Vec<_>>(),
-            &self.device,
-        )?
-        .unsqueeze(0)?;
+    fn label(self) -> &'static str {
+        match self {
+            Self::Mean => "mean",
+            Self::Cls => "cls",
+            Self::Max => "max",
+        }
+    }
+}
 
-        let attention_mask = Tensor::new(
-            tokens.get_attention_mask().iter().map(|&i| i as u8).collect::<Vec<_>>(),
-            &self.device,
-        )?
-        .unsqueeze(0)?;
+/// Slice `text` to the `[start, end)` range counted in characters, so spans
+/// never split a multibyte sequence. Missing bounds default to the ends.
+fn char_span(text: &str, start: Option<u32>, end: Option<u32>) -> Result<&str, Status> {
+    let char_count = text.chars().count();
+    let start = start.map_or(0, |s| s as usize);
+    let end = end.map_or(char_count, |e| e as usize);
+    if start > end || end > char_count {
+        return Err(Status::invalid_argument(format!(
+            "Character range [{}, {}) is invalid for text of {} characters",
+            start, end, char_count
+        )));
+    }
 
-        // Generate embeddings
-        let embeddings = model.forward(&input_ids, &attention_mask, None)?;
+    let byte_offset = |chars: usize| text.char_indices().nth(chars).map_or(text.len(), |(i, _)| i);
+    Ok(&text[byte_offset(start)..byte_offset(end)])
+}
 
-        // Mean pooling (average all token embeddings)
-        let embeddings = embeddings.mean(1)?;
+/// Scale `vector` to unit L2 norm in place.
+fn l2_normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
 
-        // Squeeze batch dimension and convert to Vec<f32>
-        let result = embeddings.squeeze(0)?.to_vec1::<f32>()?;
-        Ok(result)
+/// Sparsity and L1/L2 ratio of `vector` (see `VectorStats`).
+fn vector_stats(vector: &[f32], threshold: f32) -> VectorStats {
+    let near_zero = vector.iter().filter(|x| x.abs() < threshold).count();
+    let l1: f32 = vector.iter().map(|x| x.abs()).sum();
+    let l2 = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    VectorStats {
+        sparsity: near_zero as f32 / vector.len().max(1) as f32,
+        l1_l2_ratio: if l2 > 0.0 { l1 / l2 } else { 0.0 },
+        threshold,
     }
 }
 
-// Service implementation
-struct LLMServiceImpl {
-    model: Arc<Mutex<EmbeddingModel>>,
+/// `vector` as little-endian f32 bytes, for `EmbedResponse.raw_vector`.
+fn le_bytes(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
 }
 
-impl Default for LLMServiceImpl {
-    fn default() -> Self {
-        Self {
-            model: Arc::new(Mutex::new(EmbeddingModel::new())),
+/// Clamp every component of `vector` to `[-limit, limit]`.
+fn clip_components(vector: &mut [f32], limit: f32) {
+    vector.iter_mut().for_each(|x| *x = x.clamp(-limit, limit));
+}
+
+/// The `percentile` (0, 100) of `vector`'s absolute component values, by the
+/// nearest-rank method.
+fn abs_percentile(vector: &[f32], percentile: f32) -> f32 {
+    let mut magnitudes: Vec<f32> = vector.iter().map(|x| x.abs()).collect();
+    magnitudes.sort_by(f32::total_cmp);
+    let rank = ((percentile / 100.0) * magnitudes.len() as f32).ceil() as usize;
+    magnitudes.get(rank.saturating_sub(1)).copied().unwrap_or(0.0)
+}
+
+/// Append the MIPS reduction's extra coordinate. Documents become
+/// `[x / M, sqrt(1 - |x|^2 / M^2)]` and queries `[q / |q|, 0]`, so every
+/// augmented vector has unit norm and `q' . x' = (q . x) / (M |q|)`: inner
+/// product, cosine and L2 distance over the augmented vectors all rank
+/// documents by `q . x`.
+fn mips_augment(vector: &mut Vec<f32>, role: &str, max_norm: f32) -> Result<(), Status> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match role {
+        "document" => {
+            if norm > max_norm * (1.0 + 1e-6) {
+                return Err(Status::invalid_argument(format!(
+                    "vector norm {} exceeds mips_max_norm {}",
+                    norm, max_norm
+                )));
+            }
+            vector.iter_mut().for_each(|x| *x /= max_norm);
+            let ratio = norm / max_norm;
+            vector.push((1.0 - ratio * ratio).max(0.0).sqrt());
+        }
+        "query" => {
+            l2_normalize(vector);
+            vector.push(0.0);
+        }
+        other => {
+            return Err(Status::invalid_argument(format!(
+                "Unknown mips_role '{}' (expected document or query)",
+                other
+            )))
         }
     }
+    Ok(())
 }
 
-#[tonic::async_trait]
-impl LlmService for LLMServiceImpl {
-    async fn init_model(&self, request: Request<InitRequest>) -> Result<Response<InitResponse>, Status> {
-        let req = request.into_inner();
-        let mut model = self.model.lock().await;
-
-        match model.load(&req.model_path) {
-            Ok(_) => Ok(Response::new(InitResponse {
-                success: true,
-                message: format!("Embedding model loaded from {}", req.model_path),
-            })),
-            Err(e) => Ok(Response::new(InitResponse {
-                success: false,
-                message: format!("Failed to load model: {}", e),
-            })),
+/// SimHash of `vector` against `bits` Gaussian hyperplanes drawn from `seed`,
+/// packed least significant bit first. The hyperplanes come from the same
+/// seeded generator as `random_projection`, so every replica agrees.
+fn lsh_code(vector: &[f32], bits: usize, seed: u64) -> anyhow::Result<Vec<u8>> {
+    let planes = random_projection(vector.len(), bits, seed, &Device::Cpu)?;
+    let projected = Tensor::new(vector, &Device::Cpu)?
+        .unsqueeze(0)?
+        .matmul(&planes)?
+        .squeeze(0)?
+        .to_vec1::<f32>()?;
+    let mut code = vec![0u8; bits.div_ceil(8)];
+    for (i, &x) in projected.iter().enumerate() {
+        if x > 0.0 {
+            code[i / 8] |= 1 << (i % 8);
         }
     }
+    Ok(code)
+}
 
-    type GenerateStream = tokio_stream::wrappers::ReceiverStream<Result<GenerateResponse, Status>>;
+/// Cosine similarity of two equally sized vectors; 0 when either is all zeros.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
 
-    async fn generate(&self, request: Request<GenerateRequest>) -> Result<Response<Self::GenerateStream>, Status> {
-        let req = request.into_inner();
-        let model = self.model.lock().await;
+/// What BatchEmbed does with empty or whitespace-only texts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum EmptyInputPolicy {
+    #[default]
+    Error,
+    Skip,
+    ZeroVector,
+}
 
-        let (tx, rx) = tokio::sync::mpsc::channel(4);
+impl EmptyInputPolicy {
+    fn parse(name: &str) -> Result<Self, Status> {
+        match name {
+            "" | "error" => Ok(Self::Error),
+            "skip" => Ok(Self::Skip),
+            "zero_vector" => Ok(Self::ZeroVector),
+            other => Err(Status::invalid_argument(format!(
+                "Unknown empty_input_policy '{}' (expected error, skip or zero_vector)",
+                other
+            ))),
+        }
+    }
+}
 
-        let prompt = req.prompt;
-        let is_loaded = model.model.is_some();
+/// Drop the entries at `indices` (ascending) from `items`.
+fn remove_indices<T>(items: &mut Vec<T>, indices: &[usize]) {
+    let mut index = 0;
+    items.retain(|_| {
+        let keep = indices.binary_search(&index).is_err();
+        index += 1;
+        keep
+    });
+}
 
-        let embedding_result: anyhow::Result<Vec<f32>> = if is_loaded {
-            model.embed(&prompt)
-        } else {
-            Ok(vec![])
-        };
+/// How SearchCorpus scores a corpus vector against the query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Metric {
+    Cosine,
+    Dot,
+    Euclidean,
+    Manhattan,
+}
 
-        drop(model);
+impl Metric {
+    fn parse(name: &str) -> Result<Self, Status> {
+        match name {
+            "" | "cosine" => Ok(Self::Cosine),
+            "dot" => Ok(Self::Dot),
+            "euclidean" => Ok(Self::Euclidean),
+            "manhattan" => Ok(Self::Manhattan),
+            other => Err(Status::invalid_argument(format!(
+                "Unknown metric '{}' (expected cosine, dot, euclidean or manhattan)",
+                other
+            ))),
+        }
+    }
 
-        tokio::spawn(async move {
-            if !is_loaded {
-                let _ = tx.send(Err(Status::failed_precondition("Model not initialized"))).await;
-                return;
-            }
+    fn score(self, a: &[f32], b: &[f32]) -> f32 {
+        let pairs = a.iter().zip(b);
+        match self {
+            Self::Cosine => cosine_similarity(a, b),
+            Self::Dot => pairs.map(|(x, y)| x * y).sum(),
+            Self::Euclidean => pairs.map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+            Self::Manhattan => pairs.map(|(x, y)| (x - y).abs()).sum(),
+        }
+    }
 
-            match embedding_result {
-                Ok(embedding) => {
-                    if embedding.is_empty() {
-                        let _ = tx.send(Err(Status::internal("Failed to generate embedding"))).await;
-                        return;
-                    }
+    /// Whether `score` is a similarity (best first = highest) rather than a
+    /// distance.
+    fn is_similarity(self) -> bool {
+        matches!(self, Self::Cosine | Self::Dot)
+    }
+}
 
-                    // Show embedding info
-                    let non_zero_count = embedding.iter().filter(|&&x| x.abs() > 1e-6).count();
-                    let max_val = embedding.iter().fold(0.0f32, |a, &b| a.max(b.abs()));
-                    let min_val = embedding.iter().fold(0.0f32, |a, &b| a.min(b.abs()));
+/// Element-wise average of equally sized vectors.
+fn mean_vector(vectors: &[&[f32]]) -> Vec<f32> {
+    let dim = vectors.first().map_or(0, |v| v.len());
+    let mut mean = vec![0.0f32; dim];
+    for vector in vectors {
+        for (acc, &x) in mean.iter_mut().zip(vector.iter()) {
+            *acc += x;
+        }
+    }
+    let n = vectors.len().max(1) as f32;
+    mean.iter_mut().for_each(|x| *x /= n);
+    mean
+}
 
-                    let header = format!(
-                        "Embedding generated for: '{}'\nDim: {} | Non-zero: {} | Range: [{:.4}, {:.4}]\n\nVector (hex):\n",
-                        prompt,
-                        embedding.len(),
-                        non_zero_count,
-                        min_val,
-                        max_val
-                    );
+/// Weight-normalized average: `sum(w_i * v_i) / sum(w_i)`.
+fn weighted_mean(vectors: &[Vec<f32>], weights: &[f32]) -> Vec<f32> {
+    let dim = vectors.first().map_or(0, |v| v.len());
+    let total: f32 = weights.iter().sum();
+    let mut mean = vec![0.0f32; dim];
+    for (vector, &weight) in vectors.iter().zip(weights) {
+        for (acc, &x) in mean.iter_mut().zip(vector) {
+            *acc += weight * x;
+        }
+    }
+    mean.iter_mut().for_each(|x| *x /= total);
+    mean
+}
 
-                    // Send header
-                    for ch in header.chars() {
-                        if tx.send(Ok(GenerateResponse {
-                            text: ch.to_string(),
-                            done: false,
-                            tokens_generated: 0,
-                        })).await.is_err() {
-                            return;
-                        }
-                        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
-                    }
+/// Centroid per distinct group key, in order of first appearance.
+fn group_centroids(group_keys: &[String], vectors: &[Vec<f32>]) -> Vec<GroupCentroid> {
+    let mut order: Vec<&str> = Vec::new();
+    let mut members: std::collections::HashMap<&str, Vec<&[f32]>> = std::collections::HashMap::new();
+    for (key, vector) in group_keys.iter().zip(vectors) {
+        members
+            .entry(key.as_str())
+            .or_insert_with(|| {
+                order.push(key.as_str());
+                Vec::new()
+            })
+            .push(vector.as_slice());
+    }
 
-                    // Send embedding vector in hex format (8 values per line)
-                    for (i, val) in embedding.iter().enumerate() {
-                        let hex_val = format!("{:08x}", val.to_bits());
-                        let comma = if (i + 1) % 8 == 0 { "\n" } else { " " };
-                        let text = format!("{}{}", hex_val, comma);
+    order
+        .into_iter()
+        .map(|key| {
+            let group = &members[key];
+            GroupCentroid {
+                group_key: key.to_string(),
+                vector: mean_vector(group),
+                count: group.len() as i32,
+            }
+        })
+        .collect()
+}
 
-                        if tx.send(Ok(GenerateResponse {
-                            text,
-                            done: false,
-                            tokens_generated: i as i32,
-                        })).await.is_err() {
-                            return;
-                        }
-                        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
-                    }
+/// Resolve the requested device name. Metal is best-effort: when this build or
+/// host can't provide it we warn and fall back to CPU so the load still succeeds.
+/// CUDA names a specific GPU ("cuda" is "cuda:0"), so an unavailable one fails
+/// the load instead of quietly running on the CPU.
+fn select_device(name: &str) -> anyhow::Result<Device> {
+    open_device(name, Device::new_metal, Device::new_cuda)
+}
 
-                    // Send done signal
-                    let _ = tx.send(Ok(GenerateResponse {
-                        text: String::new(),
-                        done: true,
-                        tokens_generated: embedding.len() as i32,
-                    })).await;
-                }
-                Err(e) => {
-                    let _ = tx.send(Err(Status::internal(format!("Embedding error: {}", e)))).await;
-                }
+/// [`select_device`] with the backends' constructors passed in, so the
+/// fallback rules can be exercised whatever this build or host supports.
+fn open_device(
+    name: &str,
+    new_metal: impl FnOnce(usize) -> candle_core::Result<Device>,
+    new_cuda: impl FnOnce(usize) -> candle_core::Result<Device>,
+) -> anyhow::Result<Device> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "" | "cpu" => Ok(Device::Cpu),
+        "metal" => match new_metal(0) {
+            Ok(device) => Ok(device),
+            Err(e) => {
+                tracing::warn!("Metal device requested but unavailable ({}), falling back to CPU", e);
+                Ok(Device::Cpu)
             }
-        });
+        },
+        cuda if cuda == "cuda" || cuda.starts_with("cuda:") => {
+            let ordinal = match cuda.strip_prefix("cuda:") {
+                Some(index) => index
+                    .parse::<usize>()
+                    .map_err(|_| anyhow::anyhow!("Invalid CUDA device '{}' (expected cuda:<index>)", cuda))?,
+                None => 0,
+            };
+            new_cuda(ordinal).map_err(|e| {
+                anyhow::anyhow!(
+                    "CUDA device {} unavailable ({}); build with the cuda feature on a host with that GPU",
+                    ordinal,
+                    e
+                )
+            })
+        }
+        other => anyhow::bail!("Unsupported device '{}' (expected \"cpu\", \"cuda[:N]\" or \"metal\")", other),
+    }
+}
 
-        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+fn device_label(device: &Device) -> &'static str {
+    match device {
+        Device::Cpu => "cpu",
+        Device::Cuda(_) => "cuda",
+        Device::Metal(_) => "metal",
     }
+}
 
-    async fn embed(&self, request: Request<EmbedRequest>) -> Result<Response<EmbedResponse>, Status> {
-        let req = request.into_inner();
-        let model = self.model.lock().await;
+fn dtype_label(dtype: DType) -> &'static str {
+    match dtype {
+        DType::F16 => "f16",
+        DType::BF16 => "bf16",
+        _ => "f32",
+    }
+}
 
-        let result = if model.model.is_some() {
-            match model.embed(&req.text) {
-                Ok(vector) => Ok(EmbedResponse {
-                    vector,
-                    dim: model.embedding_dim as i32,
-                }),
-                Err(e) => Err(Status::internal(format!("Embedding error: {}", e))),
+/// `InitRequest.dtype`; `None` leaves the choice to `mixed_precision`.
+fn parse_dtype(name: &str) -> anyhow::Result<Option<DType>> {
+    match name.to_ascii_lowercase().as_str() {
+        "" => Ok(None),
+        "f32" => Ok(Some(DType::F32)),
+        "f16" => Ok(Some(DType::F16)),
+        "bf16" => Ok(Some(DType::BF16)),
+        _ => anyhow::bail!("Unknown dtype '{}' (expected f32, f16 or bf16)", name),
+    }
+}
+
+/// The weight dtype for a load: an explicit `dtype` wins, else
+/// `mixed_precision` picks F16 off the CPU. Hidden states are cast back to
+/// F32 before pooling either way (see [`EmbeddingModel::forward`]).
+fn model_dtype(dtype: Option<DType>, mixed_precision: bool, on_cpu: bool) -> DType {
+    match (dtype, mixed_precision, on_cpu) {
+        (Some(dtype), mixed_precision, _) => {
+            if mixed_precision {
+                tracing::warn!("mixed_precision ignored: dtype {} was given explicitly", dtype_label(dtype));
             }
+            dtype
+        }
+        (None, false, _) => DType::F32,
+        (None, true, true) => {
+            tracing::warn!("mixed_precision ignored on cpu: half precision is slower than F32 there");
+            DType::F32
+        }
+        (None, true, false) => DType::F16,
+    }
+}
+
+/// Fail the load up front when `device` can't run matmuls in `dtype`, rather
+/// than on the first forward pass.
+fn check_dtype_support(dtype: DType, device: &Device) -> anyhow::Result<()> {
+    let probe = Tensor::ones((2, 2), dtype, device).and_then(|t| t.matmul(&t));
+    probe.map(|_| ()).map_err(|e| {
+        anyhow::anyhow!(
+            "dtype {} is not supported on {}: {}. Use f32, or f16 where bf16 is unavailable \
+             (f16 has less range than bf16; bf16 less precision than f16, so expect small drift \
+             in cosine scores versus f32 either way)",
+            dtype_label(dtype),
+            device_label(device),
+            e
+        )
+    })
+}
+
+/// FNV-1a over the given parts, NUL-separated. Unlike `DefaultHasher` the
+/// output is stable across builds and restarts, which fingerprints rely on.
+fn stable_hash(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for &byte in part.as_bytes().iter().chain(std::iter::once(&0u8)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+/// Numerically stable softmax.
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|&x| (x - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|x| x / sum).collect()
+}
+
+/// Sequence-classification head of a `BertForSequenceClassification`
+/// checkpoint: the pooler (dense + tanh over [CLS]) followed by `classifier`.
+struct ClassificationHead {
+    pooler: Option<Linear>,
+    classifier: Linear,
+    labels: Vec<String>,
+}
+
+impl ClassificationHead {
+    /// Load the head when the checkpoint has one. Labels come from the
+    /// config's `id2label`, falling back to `num_labels` generic names.
+    fn load(vb: &VarBuilder, hidden_size: usize, config: &serde_json::Value) -> Option<Self> {
+        let mut labels: Vec<(usize, String)> = config
+            .get("id2label")
+            .and_then(|v| v.as_object())
+            .map(|map| {
+                map.iter()
+                    .filter_map(|(id, label)| Some((id.parse().ok()?, label.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        labels.sort_by_key(|(id, _)| *id);
+        let labels: Vec<String> = if labels.is_empty() {
+            let num_labels = config.get("num_labels").and_then(|v| v.as_u64()).unwrap_or(2) as usize;
+            (0..num_labels).map(|i| format!("LABEL_{}", i)).collect()
         } else {
-            Err(Status::failed_precondition("Model not initialized"))
+            labels.into_iter().map(|(_, label)| label).collect()
         };
 
-        result.map(Response::new)
-    }
+        let classifier = candle_nn::linear(hidden_size, labels.len(), vb.pp("classifier")).ok()?;
+        let pooler = load_pooler(vb, hidden_size);
 
-    async fn model_info(&self, _request: Request<ModelInfoRequest>) -> Result<Response<ModelInfoResponse>, Status> {
-        let model = self.model.lock().await;
-        Ok(Response::new(ModelInfoResponse {
-            model_name: if model.model.is_some() {
-                format!("{} (candle BERT)", model.model_path)
-            } else {
-                "Not loaded".to_string()
-            },
-            vocab_size: 30522,
-            context_size: 512,
-            backend: "candle".to_string(),
-        }))
+        Some(Self { pooler, classifier, labels })
     }
 
-    async fn health(&self, _request: Request<HealthRequest>) -> Result<Response<HealthResponse>, Status> {
-        let model = self.model.lock().await;
-        Ok(Response::new(HealthResponse {
-            healthy: true,
-            message: if model.model.is_some() {
-                "Embedding service is healthy (model loaded)".to_string()
-            } else {
-                "Embedding service is healthy (no model)".to_string()
-            },
-        }))
+    /// Logits for a `[1, seq, hidden]` last hidden state. The head runs in the
+    /// dtype it was loaded with; logits come back as F32.
+    fn forward(&self, hidden: &Tensor) -> anyhow::Result<Vec<f32>> {
+        let cls = hidden.narrow(1, 0, 1)?.squeeze(1)?.to_dtype(self.classifier.weight().dtype())?;
+        let pooled = match &self.pooler {
+            Some(pooler) => pooler.forward(&cls)?.tanh()?,
+            None => cls,
+        };
+        Ok(self.classifier.forward(&pooled)?.squeeze(0)?.to_dtype(DType::F32)?.to_vec1::<f32>()?)
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
+/// The BERT pooler's dense layer (applied to [CLS], followed by tanh), when
+/// the checkpoint has one.
+fn load_pooler(vb: &VarBuilder, hidden_size: usize) -> Option<Linear> {
+    ["bert.pooler.dense", "pooler.dense"]
+        .iter()
+        .find_map(|prefix| candle_nn::linear(hidden_size, hidden_size, vb.pp(*prefix)).ok())
+}
 
-    let addr = "[::0]:50051".parse()?;
-    let llm_service = LLMServiceImpl::default();
+/// Load a `[hidden_size, target_dim]` projection matrix from `.npy` or
+/// `.safetensors` (a tensor named "projection", or the file's only tensor).
+fn load_projection(path: &str, hidden_size: usize, device: &Device) -> anyhow::Result<Tensor> {
+    let matrix = if path.ends_with(".npy") {
+        Tensor::read_npy(path)?
+    } else {
+        let mut tensors = candle_core::safetensors::load(path, &Device::Cpu)?;
+        match tensors.remove("projection") {
+            Some(matrix) => matrix,
+            None if tensors.len() == 1 => tensors.into_values().next().expect("one tensor"),
+            None => anyhow::bail!("{} has several tensors and none is named \"projection\"", path),
+        }
+    };
 
-    tracing::info!("LLM Embedding Sidecar listening on {}", addr);
-    tracing::info!("Using candle for real BERT embedding models");
+    match matrix.dims() {
+        &[rows, cols] if rows == hidden_size && cols > 0 => Ok(matrix.to_dtype(DType::F32)?.to_device(device)?),
+        dims => anyhow::bail!(
+            "Projection in {} has shape {:?}, expected [{}, target_dim]",
+            path,
+            dims,
+            hidden_size
+        ),
+    }
+}
 
-    Server::builder()
-        .add_service(LlmServiceServer::new(llm_service))
-        .serve(addr)
-        .await?;
+/// Seeded `[hidden_size, target_dim]` Gaussian projection with N(0, 1/target_dim)
+/// entries. The matrix is generated on the CPU with SplitMix64 + Box-Muller, so
+/// the same seed gives the same matrix on every replica and device.
+fn random_projection(hidden_size: usize, target_dim: usize, seed: u64, device: &Device) -> anyhow::Result<Tensor> {
+    let mut state = seed;
+    let mut next_unit = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // Top 53 bits as a float in (0, 1]; never 0 so ln() stays finite.
+        ((z >> 11) + 1) as f64 / (1u64 << 53) as f64
+    };
+
+    let scale = 1.0 / (target_dim as f64).sqrt();
+    let values: Vec<f32> = (0..hidden_size * target_dim)
+        .map(|_| {
+            let (u1, u2) = (next_unit(), next_unit());
+            ((-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos() * scale) as f32
+        })
+        .collect();
+    Ok(Tensor::from_vec(values, (hidden_size, target_dim), device)?)
+}
+
+/// Non-special token ids of a possibly truncated encoding, including the
+/// overflow the tokenizer cut off, in input order.
+fn content_ids(encoding: &Encoding) -> Vec<u32> {
+    std::iter::once(encoding)
+        .chain(encoding.get_overflowing())
+        .flat_map(|e| {
+            e.get_ids()
+                .iter()
+                .zip(e.get_special_tokens_mask())
+                .filter(|(_, &special)| special == 0)
+                .map(|(&id, _)| id)
+        })
+        .collect()
+}
+
+/// `normalizer` with every lowercasing step removed, or None when nothing is
+/// left. BertNormalizer ties accent stripping to lowercasing when it isn't set
+/// explicitly, so that choice is pinned to keep accents handled as before.
+fn without_lowercase(normalizer: &NormalizerWrapper) -> Option<NormalizerWrapper> {
+    match normalizer {
+        NormalizerWrapper::Lowercase(_) => None,
+        NormalizerWrapper::BertNormalizer(bert) => {
+            let mut bert = *bert;
+            bert.strip_accents = Some(bert.strip_accents.unwrap_or(bert.lowercase));
+            bert.lowercase = false;
+            Some(NormalizerWrapper::BertNormalizer(bert))
+        }
+        NormalizerWrapper::Sequence(sequence) => {
+            let steps: Vec<_> = sequence.get_normalizers().iter().filter_map(without_lowercase).collect();
+            (!steps.is_empty()).then(|| NormalizerWrapper::Sequence(Sequence::new(steps)))
+        }
+        other => Some(other.clone()),
+    }
+}
+
+/// Identity of an init request for duplicate detection; `force_reload`
+/// itself doesn't distinguish one load from another.
+fn init_key(req: &InitRequest) -> String {
+    let req = InitRequest {
+        force_reload: false,
+        ..req.clone()
+    };
+    stable_hash(&[&format!("{:?}", req)])
+}
+
+/// Whether a download failed because the disk (or quota) is full.
+fn is_disk_full(error: &ApiError) -> bool {
+    match error {
+        ApiError::IoError(e) => e.kind() == std::io::ErrorKind::StorageFull || e.raw_os_error() == Some(28), // ENOSPC
+        ApiError::TooManyRetries(inner) => is_disk_full(inner),
+        _ => false,
+    }
+}
+
+/// The HTTP error response behind a failed hub request, if there was one.
+fn error_response(error: &ApiError) -> Option<&ureq::Response> {
+    match error {
+        ApiError::RequestError(e) => match e.as_ref() {
+            ureq::Error::Status(_, response) => Some(response),
+            _ => None,
+        },
+        ApiError::TooManyRetries(inner) => error_response(inner),
+        _ => None,
+    }
+}
+
+/// How long to back off when HuggingFace answered 429: its `Retry-After`
+/// seconds (an HTTP date, or no header, waits the default), capped.
+fn rate_limit_delay(error: &ApiError) -> Option<Duration> {
+    let response = error_response(error).filter(|r| r.status() == 429)?;
+    Some(
+        response
+            .header("Retry-After")
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map_or(DEFAULT_RATE_LIMIT_WAIT, Duration::from_secs)
+            .min(MAX_RATE_LIMIT_WAIT),
+    )
+}
+
+/// Fetch `filename` from the hub. Running out of disk is reported with the
+/// affected cache directory, and the partial downloads left in it are removed
+/// so they don't keep holding the space. Rate limiting (429) is waited out up
+/// to `SIDECAR_HF_RATE_LIMIT_RETRIES` times (default 3), honouring
+/// `Retry-After`.
+fn hub_get(api: &ApiRepo, filename: &str, repo_cache: &std::path::Path) -> anyhow::Result<std::path::PathBuf> {
+    let max_retries = std::env::var("SIDECAR_HF_RATE_LIMIT_RETRIES")
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .unwrap_or(DEFAULT_RATE_LIMIT_RETRIES);
+    fetch_with_retries(filename, repo_cache, max_retries, || api.get(filename))
+}
+
+/// [`hub_get`]'s error handling around one `fetch` of `filename`, repeated
+/// while it is rate limited and retries remain.
+fn fetch_with_retries(
+    filename: &str,
+    repo_cache: &std::path::Path,
+    max_retries: u32,
+    mut fetch: impl FnMut() -> Result<std::path::PathBuf, ApiError>,
+) -> anyhow::Result<std::path::PathBuf> {
+    let mut retries = 0;
+    loop {
+        match fetch() {
+            Ok(path) => return Ok(path),
+            Err(e) if is_disk_full(&e) => {
+                let removed = remove_partial_downloads(&repo_cache.join("blobs"));
+                tracing::error!(
+                    "Disk full downloading {} into {} (removed {} partial file(s))",
+                    filename,
+                    repo_cache.display(),
+                    removed
+                );
+                anyhow::bail!(
+                    "Disk full while downloading {} into HuggingFace cache {}; free space or point HF_HOME at a larger volume",
+                    filename,
+                    repo_cache.display()
+                )
+            }
+            Err(e) if error_response(&e).is_some_and(|r| matches!(r.status(), 401 | 403)) => {
+                let status = error_response(&e).map_or(0, |r| r.status());
+                let hint = if std::env::var("HF_TOKEN").is_ok_and(|t| !t.is_empty()) {
+                    "check that HF_TOKEN has access to it"
+                } else {
+                    "set HF_TOKEN to a token with access"
+                };
+                anyhow::bail!(
+                    "HuggingFace refused {} (HTTP {}): the model may be private or gated; {}",
+                    filename,
+                    status,
+                    hint
+                )
+            }
+            Err(e) => match rate_limit_delay(&e) {
+                Some(delay) if retries < max_retries => {
+                    retries += 1;
+                    tracing::warn!(
+                        "HuggingFace rate limited {} (429); retry {}/{} in {:?}",
+                        filename,
+                        retries,
+                        max_retries,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                }
+                Some(_) => anyhow::bail!(
+                    "Rate limited by HuggingFace (HTTP 429) downloading {} after {} retries; this is transient, retry the init later",
+                    filename,
+                    retries
+                ),
+                None => return Err(e.into()),
+            },
+        }
+    }
+}
+
+/// Index of a checkpoint split into several safetensors files.
+const SHARD_INDEX: &str = "model.safetensors.index.json";
+
+/// Distinct shard files a `model.safetensors.index.json` maps weights to, in
+/// name order.
+fn shard_names(index: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    let index: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(index)?)?;
+    let weight_map = index
+        .get("weight_map")
+        .and_then(|v| v.as_object())
+        .ok_or_else(|| anyhow::anyhow!("{} has no weight_map", SHARD_INDEX))?;
+    let mut shards: Vec<String> = weight_map.values().filter_map(|v| v.as_str().map(str::to_string)).collect();
+    shards.sort();
+    shards.dedup();
+    if shards.is_empty() {
+        anyhow::bail!("{} maps no weights", SHARD_INDEX);
+    }
+    Ok(shards)
+}
+
+/// Delete hf-hub's in-progress `*.part` files; returns how many were removed.
+fn remove_partial_downloads(blobs: &std::path::Path) -> usize {
+    let Ok(entries) = std::fs::read_dir(blobs) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "part"))
+        .filter(|path| std::fs::remove_file(path).is_ok())
+        .count()
+}
+
+const DEFAULT_RATE_LIMIT_RETRIES: u32 = 3;
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5);
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Canary embedded by DriftCheck when neither the request nor init names one.
+const DEFAULT_DRIFT_CANARY: &str = "The quick brown fox jumps over the lazy dog.";
+const DEFAULT_DRIFT_THRESHOLD: f32 = 0.99;
+const DEFAULT_ROWS_PER_CHUNK: usize = 32;
+const DEFAULT_TOP_K: usize = 10;
+const DEFAULT_LABEL_TEMPERATURE: f32 = 0.05;
+/// Explain runs one forward pass per document token, so documents are capped.
+const MAX_EXPLAIN_TOKENS: usize = 128;
+const MAX_SHORT_INPUT_REPEATS: usize = 8;
+const MAX_LSH_BITS: i32 = 1024;
+/// Texts per forward pass when a long list is embedded in batches.
+const MAX_BATCH_SIZE: usize = 64;
+#[cfg(feature = "qdrant")]
+const DEFAULT_UPSERT_BATCH: usize = 64;
+
+/// One embedded entry of the in-memory corpus.
+struct CorpusEntry {
+    id: String,
+    text: String,
+    vector: Vec<f32>,
+}
+
+/// Greedy near-duplicate removal: keep an entry only if its cosine similarity
+/// to every entry kept so far is at most `threshold`. Order is preserved.
+fn dedup_corpus(entries: Vec<CorpusEntry>, threshold: f32) -> Vec<CorpusEntry> {
+    let mut kept: Vec<CorpusEntry> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if kept.iter().all(|k| cosine_similarity(&k.vector, &entry.vector) <= threshold) {
+            kept.push(entry);
+        }
+    }
+    kept
+}
+
+// Real embedding model using candle
+struct EmbeddingModel {
+    model: Option<Box<dyn encoder::Embed>>,
+    classifier: Option<ClassificationHead>,
+    tokenizer: Option<Tokenizer>,
+    device: Device,
+    /// Weights and encoder activations; pooling always runs in F32.
+    model_dtype: DType,
+    model_path: String,
+    embedding_dim: usize,
+    architecture: &'static str,
+    vocab_size: usize,
+    max_position_embeddings: usize,
+    drift_canary: String,
+    drift_reference: Vec<f32>,
+    pooling_ignore_ids: std::collections::HashSet<u32>,
+    exclude_special_tokens: bool,
+    special_token_ids: std::collections::HashSet<u32>,
+    projection: Option<Tensor>,
+    fingerprint: String,
+    /// Hash of the InitRequest that produced the current load.
+    init_key: String,
+    /// Vectors depend on the model, so the corpus is dropped on every load.
+    corpus: Vec<CorpusEntry>,
+    /// Zero-shot labels (id = label name); dropped on every load like the corpus.
+    labels: Vec<CorpusEntry>,
+    qdrant_url: String,
+    qdrant_collection: String,
+    invalid_text_policy: InvalidTextPolicy,
+    truncation_policy: TruncationPolicy,
+    sequence_mismatch: SequenceMismatch,
+    pooling: Pooling,
+    /// Pooler applied to the CLS vector under `Pooling::Cls` (`use_pooler`).
+    pooler: Option<Linear>,
+    /// Embed's normalize default when the request leaves it unset.
+    normalize: bool,
+    /// Instruction prefixes for `input_type` "query" and "passage".
+    query_prefix: String,
+    passage_prefix: String,
+    /// Results of [`Self::embed_with_policy`] by input text, when enabled.
+    embed_cache: Option<cache::LruCache<(Vec<f32>, bool, usize)>>,
+    /// Smaller model Embed retries with when the primary fails inference.
+    fallback: Option<Box<EmbeddingModel>>,
+    /// Model B of an A/B split, serving `variant_percent` of keyed Embeds.
+    variant: Option<Box<EmbeddingModel>>,
+    variant_percent: u32,
+}
+
+impl EmbeddingModel {
+    fn new() -> Self {
+        Self {
+            model: None,
+            classifier: None,
+            tokenizer: None,
+            device: Device::Cpu,
+            model_dtype: DType::F32,
+            model_path: String::new(),
+            embedding_dim: 384,
+            architecture: "",
+            vocab_size: 0,
+            max_position_embeddings: 512,
+            drift_canary: String::new(),
+            drift_reference: Vec::new(),
+            pooling_ignore_ids: std::collections::HashSet::new(),
+            exclude_special_tokens: false,
+            special_token_ids: std::collections::HashSet::new(),
+            projection: None,
+            fingerprint: String::new(),
+            init_key: String::new(),
+            corpus: Vec::new(),
+            labels: Vec::new(),
+            qdrant_url: String::new(),
+            qdrant_collection: String::new(),
+            invalid_text_policy: InvalidTextPolicy::Allow,
+            truncation_policy: TruncationPolicy::Flag,
+            sequence_mismatch: SequenceMismatch::Error,
+            pooling: Pooling::Mean,
+            pooler: None,
+            normalize: false,
+            query_prefix: String::new(),
+            passage_prefix: String::new(),
+            embed_cache: None,
+            fallback: None,
+            variant: None,
+            variant_percent: 0,
+        }
+    }
+
+    /// A new model loaded from `req`.
+    fn loaded(req: &InitRequest) -> anyhow::Result<Self> {
+        let mut model = Self::new();
+        model.load(req)?;
+        Ok(model)
+    }
+
+    fn load(&mut self, req: &InitRequest) -> anyhow::Result<()> {
+        let model_path = req.model_path.as_str();
+        tracing::info!("Loading embedding model from: {}", model_path);
+        let revision = if req.revision.is_empty() { "main" } else { req.revision.as_str() };
+
+        let invalid_text_policy = InvalidTextPolicy::parse(&req.invalid_text_policy)?;
+        let truncation_policy = TruncationPolicy::parse(&req.truncation_policy)?;
+        let sequence_mismatch = SequenceMismatch::parse(&req.sequence_mismatch)?;
+        let weights_loading = WeightsLoading::parse(&req.weights_loading)?;
+        let pooling = Pooling::parse(&req.pooling)?;
+        if req.use_pooler && pooling != Pooling::Cls {
+            anyhow::bail!("use_pooler requires cls pooling, got {}", pooling.label());
+        }
+        if let Some(input) = req.warmup_inputs.iter().find(|input| input.batch_size < 0) {
+            anyhow::bail!("warmup_inputs batch_size must not be negative, got {}", input.batch_size);
+        }
+        let device = select_device(&req.device)?;
+        tracing::info!("Using device: {}", device_label(&device));
+        let model_dtype = model_dtype(parse_dtype(&req.dtype)?, req.mixed_precision, device.is_cpu());
+        check_dtype_support(model_dtype, &device)?;
+
+        // The fallback lives on the CPU so accelerator OOM can't take it down
+        // with the primary. Token-id options are vocabulary-specific and stay
+        // with the primary.
+        let fallback = if req.fallback_model_path.is_empty() {
+            None
+        } else {
+            tracing::info!("Loading fallback model from: {}", req.fallback_model_path);
+            let mut fallback = EmbeddingModel::new();
+            fallback.load(&InitRequest {
+                model_path: req.fallback_model_path.clone(),
+                device: "cpu".to_string(),
+                exclude_special_tokens: req.exclude_special_tokens,
+                invalid_text_policy: req.invalid_text_policy.clone(),
+                truncation_policy: req.truncation_policy.clone(),
+                sequence_mismatch: req.sequence_mismatch.clone(),
+                pooling: req.pooling.clone(),
+                use_pooler: req.use_pooler,
+                disable_lowercase: req.disable_lowercase,
+                ..InitRequest::default()
+            })?;
+            Some(Box::new(fallback))
+        };
+
+        // Model B takes the primary's options, so the split compares models
+        // rather than configurations. It has no fallback of its own.
+        if req.variant_percent > 100 {
+            anyhow::bail!("variant_percent must be within 0..=100, got {}", req.variant_percent);
+        }
+        let variant = if req.variant_model_path.is_empty() {
+            if req.variant_percent > 0 {
+                anyhow::bail!("variant_percent set without variant_model_path");
+            }
+            None
+        } else {
+            tracing::info!(
+                "Loading variant model from: {} ({}% of keyed traffic)",
+                req.variant_model_path,
+                req.variant_percent
+            );
+            let mut variant = EmbeddingModel::new();
+            variant.load(&InitRequest {
+                model_path: req.variant_model_path.clone(),
+                variant_model_path: String::new(),
+                variant_percent: 0,
+                fallback_model_path: String::new(),
+                ..req.clone()
+            })?;
+            Some(Box::new(variant))
+        };
+
+        // A HuggingFace model ID (org/name) unless it names a local directory
+        let is_hub_id = model_path.contains('/') && !std::path::Path::new(model_path).is_dir();
+        let (mut tokenizer, config_filename, weights_filenames) = if is_hub_id {
+            // HuggingFace model ID
+            tracing::info!("Downloading model from HuggingFace: {} at revision {}", model_path, revision);
+            // HF_TOKEN wins over the token `huggingface-cli login` cached.
+            let mut api = ApiBuilder::new();
+            if let Some(token) = std::env::var("HF_TOKEN").ok().filter(|t| !t.is_empty()) {
+                api = api.with_token(Some(token));
+            }
+            let api = api.build()?;
+            let repo = Repo::with_revision(model_path.to_string(), RepoType::Model, revision.to_string());
+            let repo_cache = Cache::default().path().join(repo.folder_name());
+            let api = api.repo(repo);
+
+            let tokenizer_path = hub_get(&api, "tokenizer.json", &repo_cache)?;
+            let config_path = hub_get(&api, "config.json", &repo_cache)?;
+            // Single-file checkpoints are the common case; only look for a
+            // shard index when there is no model.safetensors.
+            let weights = match hub_get(&api, "model.safetensors", &repo_cache) {
+                Ok(path) => vec![path],
+                Err(single) => match hub_get(&api, SHARD_INDEX, &repo_cache) {
+                    Ok(index) => shard_names(&index)?
+                        .iter()
+                        .map(|shard| hub_get(&api, shard, &repo_cache))
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                    Err(_) => return Err(single),
+                },
+            };
+
+            let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(|e| anyhow::anyhow!("{}", e))?;
+            (tokenizer, config_path.to_string_lossy().to_string(), weights)
+        } else {
+            // Local path
+            tracing::info!("Loading model from local path: {}", model_path);
+            let base_path = std::path::Path::new(model_path);
+            let tokenizer_path = base_path.join("tokenizer.json");
+            let config_path = base_path.join("config.json");
+            let model_path = base_path.join("model.safetensors");
+            let index_path = base_path.join(SHARD_INDEX);
+
+            if !tokenizer_path.exists() {
+                anyhow::bail!("tokenizer.json not found in {}", base_path.display());
+            }
+            if !config_path.exists() {
+                anyhow::bail!("config.json not found in {}", base_path.display());
+            }
+            let weights = if model_path.exists() {
+                vec![model_path]
+            } else if index_path.exists() {
+                let shards: Vec<_> = shard_names(&index_path)?.iter().map(|shard| base_path.join(shard)).collect();
+                if let Some(missing) = shards.iter().find(|shard| !shard.exists()) {
+                    anyhow::bail!("{} lists {}, which is missing", index_path.display(), missing.display());
+                }
+                shards
+            } else {
+                anyhow::bail!("Neither model.safetensors nor {} found in {}", SHARD_INDEX, base_path.display());
+            };
+
+            let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|e| anyhow::anyhow!("{}", e))?;
+            (tokenizer, config_path.to_string_lossy().to_string(), weights)
+        };
+
+        // Load config
+        if req.disable_lowercase {
+            let before = tokenizer.get_normalizer().cloned();
+            let normalizer = before.as_ref().and_then(without_lowercase);
+            if format!("{:?}", normalizer) == format!("{:?}", before) {
+                tracing::warn!("disable_lowercase set, but the tokenizer does not lowercase: {:?}", before);
+            } else {
+                tracing::info!("Lowercasing disabled; tokenizer normalizer is now {:?}", normalizer);
+            }
+            tokenizer.with_normalizer(normalizer);
+        }
+
+        let raw_config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&config_filename)?)?;
+        let architecture = encoder::architecture(&raw_config)?;
+        let config = (architecture.config)(&raw_config)?;
+        if let Some(id) = req.pooling_ignore_token_ids.iter().find(|&&id| id as usize >= config.vocab_size) {
+            anyhow::bail!("pooling_ignore_token_ids contains {} but the vocabulary has {} tokens", id, config.vocab_size);
+        }
+        if req.random_projection_dim != 0 && !req.projection_path.is_empty() {
+            anyhow::bail!("projection_path and random_projection_dim cannot be combined");
+        }
+        let projection = if req.random_projection_dim != 0 {
+            let target_dim = req.random_projection_dim;
+            if target_dim < 0 || target_dim as usize > config.hidden_size {
+                anyhow::bail!(
+                    "random_projection_dim must be within 1..={} for this model, got {}",
+                    config.hidden_size,
+                    target_dim
+                );
+            }
+            tracing::info!(
+                "Projecting embeddings to {} dimensions with random seed {}",
+                target_dim,
+                req.random_projection_seed
+            );
+            Some(random_projection(config.hidden_size, target_dim as usize, req.random_projection_seed, &device)?)
+        } else if req.projection_path.is_empty() {
+            None
+        } else {
+            let projection = load_projection(&req.projection_path, config.hidden_size, &device)?;
+            tracing::info!("Projecting embeddings {:?} via {}", projection.dims(), req.projection_path);
+            Some(projection)
+        };
+        self.embedding_dim = projection.as_ref().map_or(config.hidden_size, |p| p.dims()[1]);
+        // InitRequest.context_size can only narrow the model's own context.
+        let context = match req.context_size {
+            n if n <= 0 => config.max_position_embeddings,
+            n if n as usize <= config.max_position_embeddings => n as usize,
+            n => {
+                tracing::warn!(
+                    "context_size {} exceeds the model's max_position_embeddings; using {}",
+                    n,
+                    config.max_position_embeddings
+                );
+                config.max_position_embeddings
+            }
+        };
+        self.max_position_embeddings = context;
+        self.vocab_size = config.vocab_size;
+        self.architecture = architecture.model_type;
+
+        // Truncate at the model's own context rather than whatever
+        // tokenizer.json carries (often a stale 512). Overflow is kept on the
+        // encoding for callers that window over long inputs.
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: context,
+                ..TruncationParams::default()
+            }))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+        tracing::info!(
+            "Model config: model_type={}, hidden_size={}, num_layers={}",
+            architecture.model_type,
+            config.hidden_size,
+            config.num_hidden_layers
+        );
+
+        // Load model
+        let vb = match weights_loading {
+            WeightsLoading::Mmap => unsafe {
+                VarBuilder::from_mmaped_safetensors(&weights_filenames, model_dtype, &device)?
+            },
+            WeightsLoading::Buffered => {
+                tracing::info!("Reading weights fully into memory");
+                let mut tensors = std::collections::HashMap::new();
+                for filename in &weights_filenames {
+                    tensors.extend(candle_core::safetensors::load_buffer(&std::fs::read(filename)?, &device)?);
+                }
+                VarBuilder::from_tensors(tensors, model_dtype, &device)
+            }
+        };
+        let classifier = ClassificationHead::load(&vb, config.hidden_size, &raw_config);
+        let pooler = if req.use_pooler {
+            let pooler = load_pooler(&vb, config.hidden_size)
+                .ok_or_else(|| anyhow::anyhow!("use_pooler set, but the checkpoint has no pooler weights"))?;
+            tracing::info!("Applying the checkpoint's pooler to the CLS vector");
+            Some(pooler)
+        } else {
+            None
+        };
+        if let Some(head) = &classifier {
+            tracing::info!("Classification head found with labels {:?}", head.labels);
+        }
+        let model = (architecture.load)(&raw_config, vb)?;
+
+        let special_token_ids = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect();
+
+        self.model = Some(model);
+        self.classifier = classifier;
+        self.projection = projection;
+        self.special_token_ids = special_token_ids;
+        self.exclude_special_tokens = req.exclude_special_tokens;
+        self.tokenizer = Some(tokenizer);
+        self.device = device;
+        self.model_dtype = model_dtype;
+        self.model_path = model_path.to_string();
+        self.drift_canary = req.drift_canary.clone();
+        self.drift_reference = req.drift_reference.clone();
+        self.pooling_ignore_ids = req.pooling_ignore_token_ids.iter().copied().collect();
+        if !self.pooling_ignore_ids.is_empty() {
+            tracing::info!("Excluding {} token id(s) from pooling", self.pooling_ignore_ids.len());
+        }
+
+        let weights_size: u64 = weights_filenames
+            .iter()
+            .map(|path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+            .sum();
+        self.init_key = init_key(req);
+        self.corpus.clear();
+        self.labels.clear();
+        self.qdrant_url = req.qdrant_url.clone();
+        self.qdrant_collection = req.qdrant_collection.clone();
+        self.invalid_text_policy = invalid_text_policy;
+        self.truncation_policy = truncation_policy;
+        self.sequence_mismatch = sequence_mismatch;
+        self.pooling = pooling;
+        self.pooler = pooler;
+        self.normalize = req.normalize;
+        self.query_prefix = req.query_prefix.clone();
+        self.passage_prefix = req.passage_prefix.clone();
+        self.embed_cache = (req.embed_cache_size > 0).then(|| cache::LruCache::new(req.embed_cache_size as usize));
+        self.fallback = fallback;
+        self.variant = variant;
+        self.variant_percent = req.variant_percent;
+        self.fingerprint = self.output_fingerprint(req, weights_size);
+        tracing::info!("Model fingerprint: {}", self.fingerprint);
+
+        self.warm_up(&req.warmup_inputs);
+        tracing::info!("Embedding model loaded successfully");
+        Ok(())
+    }
+
+    /// Hash of everything that shapes the output vectors, for a model whose
+    /// fields `load` has already set from `req`. The weights files' total
+    /// size stands in for hashing their (large) contents.
+    fn output_fingerprint(&self, req: &InitRequest, weights_size: u64) -> String {
+        let mut ignore_ids: Vec<u32> = self.pooling_ignore_ids.iter().copied().collect();
+        ignore_ids.sort_unstable();
+        stable_hash(&[
+            &self.model_path,
+            if req.revision.is_empty() { "main" } else { req.revision.as_str() },
+            dtype_label(self.model_dtype),
+            self.pooling.label(),
+            if self.pooler.is_some() { "pooler" } else { "no-pooler" },
+            &format!("{:?}", ignore_ids),
+            &weights_size.to_string(),
+            &req.projection_path,
+            &format!("random:{}:{}", req.random_projection_dim, req.random_projection_seed),
+            &self.embedding_dim.to_string(),
+            if self.exclude_special_tokens { "content-only" } else { "all-tokens" },
+            &format!("{:?}", self.invalid_text_policy),
+            if req.disable_lowercase { "cased" } else { "tokenizer-case" },
+            self.architecture,
+            &format!("context:{}", self.max_position_embeddings),
+            &format!("{:?}", self.truncation_policy),
+            &format!("{:?}", self.sequence_mismatch),
+            if self.normalize { "normalized" } else { "unnormalized" },
+            &format!("query:{}", self.query_prefix),
+            &format!("passage:{}", self.passage_prefix),
+        ])
+    }
+
+    /// Run each warmup input once so the first real request of its shape
+    /// doesn't pay for kernel selection and allocation. Inputs with a batch
+    /// size above 1 go through `embed_batch` with the text repeated, at most
+    /// `MAX_BATCH_SIZE` times. A failed warmup is logged and doesn't fail the
+    /// load.
+    fn warm_up(&self, inputs: &[WarmupInput]) {
+        let default = [WarmupInput {
+            text: WARMUP_TEXT.to_string(),
+            batch_size: 1,
+        }];
+        let inputs = if inputs.is_empty() { &default[..] } else { inputs };
+        for input in inputs {
+            let batch_size = (input.batch_size.max(1) as usize).min(MAX_BATCH_SIZE);
+            if batch_size < input.batch_size as usize {
+                tracing::warn!("Warmup batch_size {} clamped to {}", input.batch_size, MAX_BATCH_SIZE);
+            }
+            let started = std::time::Instant::now();
+            let result = match batch_size {
+                1 => self.embed(&input.text).map(drop),
+                n => self.embed_batch(&vec![input.text.clone(); n]).map(drop),
+            };
+            match result {
+                Ok(()) => tracing::info!(
+                    "Warmup of {} chars x{} took {:?}",
+                    input.text.len(),
+                    batch_size,
+                    started.elapsed()
+                ),
+                Err(e) => tracing::warn!("Warmup of {} chars x{} failed: {}", input.text.len(), batch_size, e),
+            }
+        }
+    }
+
+    fn tokenizer(&self) -> anyhow::Result<&Tokenizer> {
+        self.tokenizer.as_ref().ok_or(anyhow::anyhow!("Tokenizer not loaded"))
+    }
+
+    fn encode(&self, text: &str) -> anyhow::Result<Encoding> {
+        let tokenizer = self.tokenizer()?;
+        timing::time(timing::Phase::Tokenize, || tokenizer.encode(text, true))
+            .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))
+    }
+
+    fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let tokens = self.encode(text)?;
+        self.embed_tokens(tokens.get_ids(), tokens.get_attention_mask())
+    }
+
+    /// Embed `text` under the truncation policy: over-long input fails under
+    /// `Error`, and the returned flag reports the cut under `Flag` only.
+    /// Served from the embed cache when one is configured.
+    fn embed_with_policy(&self, text: &str) -> anyhow::Result<(Vec<f32>, bool, usize)> {
+        if let Some(hit) = self.embed_cache.as_ref().and_then(|cache| cache.get(text)) {
+            return Ok(hit);
+        }
+        let tokens = self.encode(text)?;
+        let truncated = !tokens.get_overflowing().is_empty();
+        if truncated {
+            let special = tokens.get_special_tokens_mask().iter().filter(|&&s| s == 1).count();
+            let original = content_ids(&tokens).len() + special;
+            if self.truncation_policy == TruncationPolicy::Error {
+                return Err(InvalidInput(format!(
+                    "Input is {} tokens, over the model limit of {}",
+                    original, self.max_position_embeddings
+                ))
+                .into());
+            }
+            tracing::info!(
+                "Truncated input of {} tokens to {} (truncation_policy {:?})",
+                original,
+                tokens.get_ids().len(),
+                self.truncation_policy
+            );
+        }
+        let vector = self.embed_tokens(tokens.get_ids(), tokens.get_attention_mask())?;
+        let flagged = truncated && self.truncation_policy == TruncationPolicy::Flag;
+        let embedded = (vector, flagged, tokens.get_ids().len());
+        if let Some(cache) = &self.embed_cache {
+            cache.insert(text.to_string(), embedded.clone());
+        }
+        Ok(embedded)
+    }
+
+    /// Embed `text` split on `marker` as `[CLS] f1 [SEP] f2 [SEP] ...` with
+    /// segment ids alternating per field. Over-long input is cut at the
+    /// context (keeping the closing separator) under the truncation policy,
+    /// like [`Self::embed_with_policy`].
+    fn embed_structured(&self, text: &str, marker: &str) -> anyhow::Result<(Vec<f32>, bool, usize)> {
+        let tokenizer = self.tokenizer()?;
+        // The post-processor frames even an empty input, revealing its
+        // [CLS]/[SEP] ids without naming them.
+        let frame = tokenizer
+            .encode("", true)
+            .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+        let &[cls, sep] = frame.get_ids() else {
+            anyhow::bail!("segment_marker needs a tokenizer that frames inputs as [CLS] ... [SEP]");
+        };
+
+        let (mut ids, mut type_ids) = (vec![cls], vec![0u32]);
+        for (index, field) in text.split(marker).enumerate() {
+            if field.trim().is_empty() {
+                return Err(InvalidInput(format!(
+                    "field {} is empty; check that segment_marker does not also occur in the content",
+                    index
+                ))
+                .into());
+            }
+            let encoding = tokenizer
+                .encode(field, false)
+                .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+            let segment = (index % 2) as u32;
+            ids.extend(encoding.get_ids());
+            ids.push(sep);
+            type_ids.resize(ids.len(), segment);
+        }
+
+        let truncated = ids.len() > self.max_position_embeddings;
+        if truncated {
+            if self.truncation_policy == TruncationPolicy::Error {
+                return Err(InvalidInput(format!(
+                    "Input is {} tokens, over the model limit of {}",
+                    ids.len(),
+                    self.max_position_embeddings
+                ))
+                .into());
+            }
+            tracing::info!(
+                "Truncated structured input of {} tokens to {} (truncation_policy {:?})",
+                ids.len(),
+                self.max_position_embeddings,
+                self.truncation_policy
+            );
+            ids.truncate(self.max_position_embeddings);
+            type_ids.truncate(self.max_position_embeddings);
+            *ids.last_mut().expect("context holds at least one token") = sep;
+        }
+        let mask = vec![1u32; ids.len()];
+        let vector = self.embed_segments(&ids, Some(&type_ids), &mask)?;
+        Ok((vector, truncated && self.truncation_policy == TruncationPolicy::Flag, ids.len()))
+    }
+
+    /// Repeat `text` (space-separated) until it has at least `min_tokens`
+    /// content tokens, at most `MAX_SHORT_INPUT_REPEATS` copies. Returns None
+    /// when the text is already long enough or empty.
+    fn repeat_short(&self, text: &str, min_tokens: usize) -> anyhow::Result<Option<String>> {
+        let tokens = self
+            .tokenizer()?
+            .encode(text, false)
+            .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?
+            .len();
+        if tokens == 0 || tokens >= min_tokens {
+            return Ok(None);
+        }
+        let copies = min_tokens.div_ceil(tokens).min(MAX_SHORT_INPUT_REPEATS);
+        Ok(Some(vec![text; copies].join(" ")))
+    }
+
+    /// Embed `texts` joined by `separator` as a single input. Joins longer than
+    /// the model context are rejected up front.
+    fn embed_joined(&self, texts: &[String], separator: &str) -> anyhow::Result<Vec<f32>> {
+        let tokens = self.encode(&texts.join(separator))?;
+        if !tokens.get_overflowing().is_empty() {
+            let special = tokens.get_special_tokens_mask().iter().filter(|&&s| s == 1).count();
+            return Err(InvalidInput(format!(
+                "Joined input is {} tokens, over the model limit of {}",
+                content_ids(&tokens).len() + special,
+                self.max_position_embeddings
+            ))
+            .into());
+        }
+        self.embed_tokens(tokens.get_ids(), tokens.get_attention_mask())
+    }
+
+    /// Embed up to three context-sized windows of an over-long input (start-,
+    /// middle- and end-anchored) and keep the best: the one closest to `query`
+    /// when given, otherwise the one with the largest norm. Returns the vector
+    /// and how many windows were evaluated, plus the token count of one window.
+    /// Inputs that fit are embedded once.
+    fn embed_best_window(&self, text: &str, query: &[f32]) -> anyhow::Result<(Vec<f32>, usize, usize)> {
+        let tokens = self.encode(text)?;
+        let ids = tokens.get_ids();
+        if tokens.get_overflowing().is_empty() {
+            return Ok((self.embed_tokens(ids, tokens.get_attention_mask())?, 1, ids.len()));
+        }
+
+        // Keep the special tokens framing the sequence (e.g. [CLS] ... [SEP])
+        // on every window and slide over the full content between them.
+        let special = tokens.get_special_tokens_mask();
+        let prefix = special.iter().take_while(|&&s| s == 1).count();
+        let suffix = special.iter().rev().take_while(|&&s| s == 1).count();
+        let content = &content_ids(&tokens);
+        let width = self.max_position_embeddings.saturating_sub(prefix + suffix);
+        if width == 0 {
+            anyhow::bail!("Context of {} tokens leaves no room for content", self.max_position_embeddings);
+        }
+
+        let slack = content.len() - width;
+        let mut starts = vec![0, slack / 2, slack];
+        starts.dedup();
+
+        let mut best: Option<(f32, Vec<f32>)> = None;
+        for &start in &starts {
+            let window: Vec<u32> = ids[..prefix]
+                .iter()
+                .chain(&content[start..start + width])
+                .chain(&ids[ids.len() - suffix..])
+                .copied()
+                .collect();
+            let mask = vec![1u32; window.len()];
+            let vector = self.embed_tokens(&window, &mask)?;
+            let score = if query.is_empty() {
+                vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+            } else {
+                cosine_similarity(&vector, query)
+            };
+            if best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+                best = Some((score, vector));
+            }
+        }
+
+        let (_, vector) = best.expect("at least one window");
+        Ok((vector, starts.len(), prefix + width + suffix))
+    }
+
+    /// Occlusion importance of each `document` token for its similarity to
+    /// `query`: the drop in cosine similarity when that token alone is masked
+    /// out. Special tokens are never occluded and are left out of the result.
+    /// Returns the unoccluded similarity alongside the per-token scores.
+    fn explain(&self, query: &str, document: &str) -> anyhow::Result<(f32, Vec<TokenImportance>)> {
+        let encoding = self
+            .tokenizer()?
+            .encode_char_offsets(document, true)
+            .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+        if encoding.len() > MAX_EXPLAIN_TOKENS {
+            return Err(InvalidInput(format!(
+                "Document is {} tokens; Explain is limited to {}",
+                encoding.len(),
+                MAX_EXPLAIN_TOKENS
+            ))
+            .into());
+        }
+
+        let query = self.embed(query)?;
+        let ids = encoding.get_ids();
+        let mut mask = encoding.get_attention_mask().to_vec();
+        let baseline = cosine_similarity(&query, &self.embed_tokens(ids, &mask)?);
+
+        let mut importances = Vec::with_capacity(ids.len());
+        for (position, &special) in encoding.get_special_tokens_mask().iter().enumerate() {
+            if special != 0 || mask[position] == 0 {
+                continue;
+            }
+            mask[position] = 0;
+            let occluded = self.embed_tokens(ids, &mask);
+            mask[position] = 1;
+            // A single-token document has nothing left to pool once occluded.
+            let similarity = match occluded {
+                Ok(vector) => cosine_similarity(&query, &vector),
+                Err(e) if e.downcast_ref::<InvalidInput>().is_some() => 0.0,
+                Err(e) => return Err(e),
+            };
+            let (start, end) = encoding.get_offsets()[position];
+            importances.push(TokenImportance {
+                token: encoding.get_tokens()[position].clone(),
+                char_start: start as u32,
+                char_end: end as u32,
+                importance: baseline - similarity,
+            });
+        }
+        Ok((baseline, importances))
+    }
+
+    /// Last hidden state (`[1, seq, hidden]`, F32 whatever the model dtype)
+    /// for one tokenized sequence.
+    fn forward(&self, ids: &[u32], attention_mask: &[u32]) -> anyhow::Result<Tensor> {
+        self.forward_segments(ids, None, attention_mask)
+    }
+
+    /// [`Self::forward`] with optional segment ids, for sentence pairs.
+    fn forward_segments(
+        &self,
+        ids: &[u32],
+        type_ids: Option<&[u32]>,
+        attention_mask: &[u32],
+    ) -> anyhow::Result<Tensor> {
+        let model = self.model.as_ref().ok_or(anyhow::anyhow!("Model not loaded"))?;
+
+        let input_ids = Tensor::new(
+            ids.iter().map(|&i| i as i64).collect::<Vec<_>>(),
+            &self.device,
+        )?
+        .unsqueeze(0)?;
+
+        // Encoders take a binary (1 = attend, 0 = padding) mask and derive
+        // their additive -inf mask themselves; handing them an additive mask
+        // would double-apply the conversion and leave padding unmasked.
+        let attention_mask_tensor = Tensor::new(
+            attention_mask.iter().map(|&i| i as u8).collect::<Vec<_>>(),
+            &self.device,
+        )?
+        .unsqueeze(0)?;
+
+        let hidden = match type_ids {
+            Some(type_ids) => {
+                let type_ids = Tensor::new(
+                    type_ids.iter().map(|&i| i as i64).collect::<Vec<_>>(),
+                    &self.device,
+                )?
+                .unsqueeze(0)?;
+                model.forward_pair(&input_ids, &type_ids, &attention_mask_tensor)?
+            }
+            None => model.forward(&input_ids, &attention_mask_tensor)?,
+        };
+        Ok(hidden.to_dtype(DType::F32)?)
+    }
+
+    /// Per-token hidden states for an encoded sequence, one row per token.
+    fn token_embeddings(&self, tokens: &Encoding) -> anyhow::Result<Vec<Vec<f32>>> {
+        let hidden = self.forward(tokens.get_ids(), tokens.get_attention_mask())?;
+        Ok(hidden.squeeze(0)?.to_vec2::<f32>()?)
+    }
+
+    /// Mean of the hidden-state rows in each `[start, end)` token range, from a
+    /// single forward pass. Ranges must already be validated against the
+    /// encoding's length.
+    fn span_embeddings(&self, tokens: &Encoding, ranges: &[(usize, usize)]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let hidden = self.forward(tokens.get_ids(), tokens.get_attention_mask())?.squeeze(0)?;
+        let spans = ranges
+            .iter()
+            .map(|&(start, end)| hidden.narrow(0, start, end - start)?.mean(0))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        Ok(Tensor::stack(&spans, 0)?.to_vec2::<f32>()?)
+    }
+
+    /// Like [`Self::token_embeddings`] but hands rows to `emit` in chunks of
+    /// `rows_per_chunk`, copying only one chunk to host memory at a time.
+    /// `emit` gets the row offset and returns false to stop early.
+    fn token_embedding_chunks(
+        &self,
+        tokens: &Encoding,
+        rows_per_chunk: usize,
+        mut emit: impl FnMut(usize, Vec<Vec<f32>>) -> bool,
+    ) -> anyhow::Result<()> {
+        let hidden = self.forward(tokens.get_ids(), tokens.get_attention_mask())?.squeeze(0)?;
+        let rows = hidden.dim(0)?;
+        for offset in (0..rows).step_by(rows_per_chunk.max(1)) {
+            let len = rows_per_chunk.min(rows - offset);
+            if !emit(offset, hidden.narrow(0, offset, len)?.to_vec2::<f32>()?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Classification logits for `text` from the checkpoint's head.
+    fn classify(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let head = self
+            .classifier
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Loaded model has no sequence-classification head"))?;
+        let tokens = self.encode(text)?;
+        let hidden = self.forward(tokens.get_ids(), tokens.get_attention_mask())?;
+        head.forward(&hidden)
+    }
+
+    /// Cross-encoder relevance of each passage to `query`, in passage order.
+    /// Each pair is encoded as one sequence with segment ids and scored by
+    /// the classification head (see `RerankResponse.scores`).
+    fn rerank(&self, query: &str, passages: &[String]) -> anyhow::Result<Vec<f32>> {
+        let head = self
+            .classifier
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Loaded model has no sequence-classification head"))?;
+        passages
+            .iter()
+            .map(|passage| {
+                let tokens = self
+                    .tokenizer()?
+                    .encode((query, passage.as_str()), true)
+                    .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+                let hidden =
+                    self.forward_segments(tokens.get_ids(), Some(tokens.get_type_ids()), tokens.get_attention_mask())?;
+                let logits = head.forward(&hidden)?;
+                Ok(match logits.as_slice() {
+                    [logit] => *logit,
+                    _ => softmax(&logits).last().copied().unwrap_or(0.0),
+                })
+            })
+            .collect()
+    }
+
+    /// Whether a position contributes to pooling: unmasked tokens that are not
+    /// on the ignore list and, if configured, not special tokens.
+    fn pools(&self, id: u32, mask: u32) -> bool {
+        mask != 0
+            && !self.pooling_ignore_ids.contains(&id)
+            && !(self.exclude_special_tokens && self.special_token_ids.contains(&id))
+    }
+
+    /// Embed `texts` with a single forward pass over the batch padded to its
+    /// longest sequence. Pooling matches `embed_tokens`: a masked mean over the
+    /// positions `pools` selects. Vectors come back in input order.
+    fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let model = self.model.as_ref().ok_or(anyhow::anyhow!("Model not loaded"))?;
+        let encodings = self
+            .tokenizer()?
+            .encode_batch(texts.iter().map(String::as_str).collect::<Vec<_>>(), true)
+            .map_err(|e| anyhow::anyhow!("Tokenization failed: {}", e))?;
+
+        let batch = encodings.len();
+        let seq = encodings.iter().map(Encoding::len).max().unwrap_or(0);
+        let mut ids = vec![0i64; batch * seq];
+        let mut mask = vec![0u8; batch * seq];
+        let mut weights = vec![0f32; batch * seq];
+        for (row, encoding) in encodings.iter().enumerate() {
+            let offset = row * seq;
+            for (position, (&id, &attend)) in encoding.get_ids().iter().zip(encoding.get_attention_mask()).enumerate() {
+                ids[offset + position] = id as i64;
+                mask[offset + position] = attend as u8;
+                if self.pools(id, attend) {
+                    weights[offset + position] = 1.0;
+                }
+            }
+            // Same guard as embed_tokens: an empty pool would divide by zero.
+            if self.pooling != Pooling::Cls && weights[offset..offset + seq].iter().all(|&w| w == 0.0) {
+                return Err(InvalidInput(format!(
+                    "text {}: no tokens left to pool over after applying the attention mask and ignore list",
+                    row
+                ))
+                .into());
+            }
+        }
+
+        let input_ids = Tensor::from_vec(ids, (batch, seq), &self.device)?;
+        let attention_mask = Tensor::from_vec(mask, (batch, seq), &self.device)?;
+        let hidden = model.forward(&input_ids, &attention_mask)?.to_dtype(DType::F32)?;
+
+        let weights = if self.pooling == Pooling::Cls || self.aligned(&hidden, seq)? {
+            Tensor::from_vec(weights, (batch, seq, 1), &self.device)?
+        } else {
+            Tensor::ones((batch, hidden.dim(1)?, 1), DType::F32, &self.device)?
+        };
+        let mut pooled = match self.pooling {
+            Pooling::Mean => hidden.broadcast_mul(&weights)?.sum(1)?.broadcast_div(&weights.sum(1)?)?,
+            Pooling::Cls => self.pool_cls(&hidden)?,
+            Pooling::Max => {
+                // Positions outside the pool (padding included) can't win the max.
+                let excluded = hidden.ones_like()?.affine(f64::NEG_INFINITY, 0.0)?;
+                weights.broadcast_as(hidden.shape())?.ne(0f32)?.where_cond(&hidden, &excluded)?.max(1)?
+            }
+        };
+        if let Some(projection) = &self.projection {
+            pooled = pooled.matmul(projection)?;
+        }
+
+        // One device-to-host copy for the whole batch.
+        let vectors = pooled.to_vec2::<f32>()?;
+        if vectors.iter().flatten().any(|x| !x.is_finite()) {
+            anyhow::bail!("Model produced non-finite values in the embedding");
+        }
+        Ok(vectors)
+    }
+
+    /// [`Self::embed_batch`] over `texts` in batches of at most
+    /// `MAX_BATCH_SIZE`, so a long list doesn't pad into one huge tensor.
+    fn embed_batches(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(MAX_BATCH_SIZE) {
+            vectors.extend(self.embed_batch(batch)?);
+        }
+        Ok(vectors)
+    }
+
+    /// Whether `hidden` (`[batch, seq, hidden]`) has one position per input
+    /// token. A mismatch fails under `SequenceMismatch::Error`; under
+    /// `PoolAll` it is logged and the caller pools over every position.
+    fn aligned(&self, hidden: &Tensor, input_len: usize) -> anyhow::Result<bool> {
+        let output_len = hidden.dim(1)?;
+        if output_len == input_len {
+            return Ok(true);
+        }
+        match self.sequence_mismatch {
+            SequenceMismatch::Error => anyhow::bail!(
+                "Encoder returned {} positions for {} input tokens, so the attention mask can't be applied; \
+                 set sequence_mismatch to pool_all to pool over every position",
+                output_len,
+                input_len
+            ),
+            SequenceMismatch::PoolAll => {
+                tracing::warn!(
+                    "Encoder returned {} positions for {} input tokens; pooling over all of them unmasked",
+                    output_len,
+                    input_len
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// The model serving `routing_key` and its A/B label ("a" or "b"; empty
+    /// without a split). Keys hash into 100 buckets and the first
+    /// `variant_percent` go to model B, so a key always lands on the same
+    /// model. Requests without a key stay on model A.
+    fn route(&self, routing_key: &str) -> (&EmbeddingModel, &'static str) {
+        let Some(variant) = self.variant.as_deref() else {
+            return (self, "");
+        };
+        if routing_key.is_empty() {
+            return (self, "a");
+        }
+        let bucket = u64::from_str_radix(&stable_hash(&[routing_key]), 16).expect("hex hash") % 100;
+        if bucket < self.variant_percent as u64 {
+            (variant, "b")
+        } else {
+            (self, "a")
+        }
+    }
+
+    /// The configured instruction prefix for `input_type`: "query", "passage",
+    /// or empty for none.
+    fn prefix_for(&self, input_type: &str) -> Result<&str, Status> {
+        match input_type {
+            "" => Ok(""),
+            "query" => Ok(&self.query_prefix),
+            "passage" => Ok(&self.passage_prefix),
+            other => Err(Status::invalid_argument(format!(
+                "Unknown input_type '{}' (expected query or passage)",
+                other
+            ))),
+        }
+    }
+
+    /// The `[batch, hidden]` CLS vectors of `hidden`, through the pooler when
+    /// one is configured.
+    fn pool_cls(&self, hidden: &Tensor) -> anyhow::Result<Tensor> {
+        let cls = hidden.narrow(1, 0, 1)?.squeeze(1)?;
+        Ok(match &self.pooler {
+            Some(pooler) => pooler
+                .forward(&cls.to_dtype(pooler.weight().dtype())?)?
+                .tanh()?
+                .to_dtype(DType::F32)?,
+            None => cls,
+        })
+    }
+
+    fn embed_tokens(&self, ids: &[u32], attention_mask: &[u32]) -> anyhow::Result<Vec<f32>> {
+        self.embed_segments(ids, None, attention_mask)
+    }
+
+    /// [`Self::embed_tokens`] with optional segment ids.
+    fn embed_segments(
+        &self,
+        ids: &[u32],
+        type_ids: Option<&[u32]>,
+        attention_mask: &[u32],
+    ) -> anyhow::Result<Vec<f32>> {
+        // Generate embeddings
+        let embeddings =
+            timing::time(timing::Phase::Forward, || self.forward_segments(ids, type_ids, attention_mask))?;
+        timing::time(timing::Phase::Pool, || self.pool_hidden(embeddings, ids, attention_mask))
+    }
+
+    /// Pool `[1, seq, hidden]` encoder output into the returned vector.
+    fn pool_hidden(&self, embeddings: Tensor, ids: &[u32], attention_mask: &[u32]) -> anyhow::Result<Vec<f32>> {
+        if self.pooling == Pooling::Cls {
+            return self.finish_pooled(self.pool_cls(&embeddings)?);
+        }
+        if !self.aligned(&embeddings, ids.len())? {
+            let pooled = match self.pooling {
+                Pooling::Max => embeddings.max(1)?,
+                _ => embeddings.mean(1)?,
+            };
+            return self.finish_pooled(pooled);
+        }
+
+        let pooled_positions: Vec<u32> = ids
+            .iter()
+            .zip(attention_mask)
+            .enumerate()
+            .filter(|&(_, (&id, &mask))| self.pools(id, mask))
+            .map(|(position, _)| position as u32)
+            .collect();
+
+        // Pooling needs at least one position; an empty set would divide by zero
+        // and yield a NaN vector.
+        if pooled_positions.is_empty() {
+            return Err(InvalidInput(
+                "no tokens left to pool over after applying the attention mask and ignore list".to_string(),
+            )
+            .into());
+        }
+        let embeddings = if pooled_positions.len() == ids.len() {
+            embeddings
+        } else {
+            let positions = Tensor::new(pooled_positions, &self.device)?;
+            embeddings.index_select(&positions, 1)?
+        };
+
+        let pooled = match self.pooling {
+            Pooling::Max => embeddings.max(1)?,
+            _ => embeddings.mean(1)?,
+        };
+        self.finish_pooled(pooled)
+    }
+
+    /// Project a pooled `[1, hidden]` vector and copy it to the host.
+    fn finish_pooled(&self, mut embeddings: Tensor) -> anyhow::Result<Vec<f32>> {
+        if let Some(projection) = &self.projection {
+            embeddings = embeddings.matmul(projection)?;
+        }
+
+        // Squeeze batch dimension and convert to Vec<f32>
+        let result = embeddings.squeeze(0)?.to_vec1::<f32>()?;
+        if result.iter().any(|x| !x.is_finite()) {
+            anyhow::bail!("Model produced non-finite values in the embedding");
+        }
+        Ok(result)
+    }
+}
+
+/// Server-side timeout ceilings per RPC, read once at startup. Each applies
+/// even when the client sends no deadline; when it does, the shorter wins.
+#[derive(Clone, Copy, Default)]
+struct RpcTimeouts {
+    embed: Option<Duration>,
+    batch: Option<Duration>,
+    generate: Option<Duration>,
+    init: Option<Duration>,
+}
+
+impl RpcTimeouts {
+    fn from_env() -> Self {
+        Self {
+            embed: env_millis("SIDECAR_EMBED_TIMEOUT_MS"),
+            batch: env_millis("SIDECAR_BATCH_TIMEOUT_MS"),
+            generate: env_millis("SIDECAR_GENERATE_TIMEOUT_MS"),
+            init: env_millis("SIDECAR_INIT_TIMEOUT_MS"),
+        }
+    }
+}
+
+const DEFAULT_SLOW_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// WARN-level log of calls slower than `SIDECAR_SLOW_MS` (default 10 s). Only
+/// sizes are logged; `SIDECAR_DEBUG_IO=1` adds the input text itself.
+#[derive(Clone, Copy)]
+struct SlowLog {
+    threshold: Duration,
+    debug_io: bool,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_SLOW_THRESHOLD,
+            debug_io: false,
+        }
+    }
+}
+
+impl SlowLog {
+    fn from_env() -> Self {
+        Self {
+            threshold: env_millis("SIDECAR_SLOW_MS").unwrap_or(DEFAULT_SLOW_THRESHOLD),
+            debug_io: std::env::var("SIDECAR_DEBUG_IO").is_ok_and(|v| v == "1" || v == "true"),
+        }
+    }
+
+    /// Log `rpc` if it has run longer than the threshold since `started`.
+    fn check(&self, rpc: &str, started: std::time::Instant, model: &EmbeddingModel, texts: &[&str]) {
+        if let Some(message) = self.report(rpc, started.elapsed(), model, texts) {
+            tracing::warn!("{}", message);
+        }
+    }
+
+    /// The warning for a call that took `elapsed`, or None under the
+    /// threshold. Tokens are only counted once a call is known to be slow.
+    fn report(&self, rpc: &str, elapsed: Duration, model: &EmbeddingModel, texts: &[&str]) -> Option<String> {
+        if elapsed < self.threshold {
+            return None;
+        }
+        let chars: usize = texts.iter().map(|t| t.chars().count()).sum();
+        let tokens: usize = texts.iter().filter_map(|t| model.encode(t).ok()).map(|e| e.len()).sum();
+        let mut message = format!(
+            "Slow {}: {} ms, {} text(s), {} chars, {} tokens, model {}",
+            rpc,
+            elapsed.as_millis(),
+            texts.len(),
+            chars,
+            tokens,
+            model.model_path
+        );
+        if self.debug_io {
+            message.push_str(&format!(", input {:?}", texts));
+        }
+        Some(message)
+    }
+}
+
+/// Read a millisecond duration from the environment; unset, zero or
+/// unparsable values mean "no limit".
+fn env_millis(name: &str) -> Option<Duration> {
+    let value = std::env::var(name).ok()?;
+    match value.trim().parse::<u64>() {
+        Ok(0) => None,
+        Ok(ms) => Some(Duration::from_millis(ms)),
+        Err(_) => {
+            tracing::warn!("Ignoring {}={:?}: expected a number of milliseconds", name, value);
+            None
+        }
+    }
+}
+
+/// Deadline the client attached via the `grpc-timeout` header, if any.
+fn client_deadline<T>(request: &Request<T>) -> Option<Duration> {
+    let value = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// The effective limit is the tighter of the server default and client deadline.
+fn effective_timeout(server: Option<Duration>, client: Option<Duration>) -> Option<Duration> {
+    match (server, client) {
+        (Some(s), Some(c)) => Some(s.min(c)),
+        (s, c) => s.or(c),
+    }
+}
+
+/// When a client last used the model. Keepalive runs deliberately don't
+/// update it, so anything keyed on idleness still sees an idle service. Reads
+/// tokio's clock, which tests can pause and advance.
+struct ActivityClock {
+    start: tokio::time::Instant,
+    last_ms: std::sync::atomic::AtomicU64,
+}
+
+impl ActivityClock {
+    fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            last_ms: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_ms.store(now, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(std::sync::atomic::Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// Requests waiting for the model lock and requests holding it, exported on
+/// Embed and BatchEmbed responses so clients can steer towards idle replicas.
+/// tonic can't attach custom trailers to a successful unary response, so the
+/// values travel as response metadata (HTTP/2 headers) instead:
+///
+/// - `x-sidecar-queue-depth`: requests waiting for the model or for an
+///   in-flight slot (see [`Admission`])
+/// - `x-sidecar-in-flight`: requests running on it
+///
+/// Both are sampled when the response is built, after its own work is done.
+#[derive(Default)]
+struct LoadGauge {
+    queued: std::sync::atomic::AtomicUsize,
+    in_flight: std::sync::atomic::AtomicUsize,
+}
+
+/// Decrements its counter when dropped, so cancelled or timed-out requests
+/// are accounted for too.
+struct GaugeGuard(Arc<LoadGauge>, fn(&LoadGauge) -> &std::sync::atomic::AtomicUsize);
+
+impl GaugeGuard {
+    fn enter(gauge: &Arc<LoadGauge>, counter: fn(&LoadGauge) -> &std::sync::atomic::AtomicUsize) -> Self {
+        counter(gauge).fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Self(gauge.clone(), counter)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        (self.1)(&self.0).fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+impl LoadGauge {
+    fn annotate<T>(&self, response: &mut Response<T>) {
+        let metadata = response.metadata_mut();
+        let queued = self.queued.load(std::sync::atomic::Ordering::Relaxed);
+        let in_flight = self.in_flight.load(std::sync::atomic::Ordering::Relaxed);
+        metadata.insert("x-sidecar-queue-depth", queued.into());
+        metadata.insert("x-sidecar-in-flight", in_flight.into());
+    }
+}
+
+/// Server-wide backpressure on model work, on top of the per-connection
+/// limits in `connection`:
+///
+/// - `SIDECAR_MAX_IN_FLIGHT`: requests running model work at once, across
+///   all connections (unset or 0: unlimited)
+/// - `SIDECAR_MAX_QUEUED`: requests allowed to wait for a slot once those are
+///   taken (unset: unlimited, bounded only by each rpc's deadline; 0: none)
+///
+/// Requests past the queue limit fail at once with `resource_exhausted`, so a
+/// burst sheds load instead of piling onto the device. A slot is held until
+/// the work finishes on the blocking pool, even when its caller has already
+/// timed out, so abandoned work still counts against the limit.
+#[derive(Default)]
+struct Admission {
+    slots: Option<Arc<tokio::sync::Semaphore>>,
+    max_queued: Option<usize>,
+}
+
+impl Admission {
+    fn from_env() -> Self {
+        let count = |name: &str| {
+            let value = std::env::var(name).ok()?;
+            value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| tracing::warn!("Ignoring {}={:?}: not a count", name, value))
+                .ok()
+        };
+        Self {
+            slots: count("SIDECAR_MAX_IN_FLIGHT")
+                .filter(|&max| max > 0)
+                .map(|max| Arc::new(tokio::sync::Semaphore::new(max))),
+            max_queued: count("SIDECAR_MAX_QUEUED"),
+        }
+    }
+
+    /// Wait for a slot, or fail when more than `max_queued` requests (this one
+    /// included, counted by `load`) are already waiting.
+    async fn acquire(&self, load: &LoadGauge) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, Status> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        if let Some(max_queued) = self.max_queued {
+            if load.queued.load(std::sync::atomic::Ordering::Relaxed) > max_queued {
+                return Err(Status::resource_exhausted(
+                    "Server is at its in-flight request limit and queue; retry with backoff",
+                ));
+            }
+        }
+        Ok(Some(slots.clone().acquire_owned().await.expect("admission semaphore is never closed")))
+    }
+}
+
+/// Text embedded once at load when no `warmup_inputs` are configured.
+const WARMUP_TEXT: &str = "warmup";
+
+/// Text embedded by keepalive runs; short so a run costs one tiny forward.
+const KEEPALIVE_TEXT: &str = "keepalive";
+
+/// Keep kernels, caches and device clocks warm under sporadic traffic: every
+/// `interval`, if no client has used the model for at least that long, run a
+/// tiny embed. A keepalive never waits for the model lock, so it doesn't queue
+/// ahead of requests; it does hold a read lock for its one short embed, and an
+/// InitModel or UnloadModel arriving meanwhile waits that out.
+async fn run_keepalive(model: Arc<RwLock<EmbeddingModel>>, activity: Arc<ActivityClock>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if activity.idle_for() < interval {
+            continue;
+        }
+        let Ok(model) = model.clone().try_read_owned() else {
+            continue;
+        };
+        if model.model.is_none() {
+            continue;
+        }
+
+        let result = tokio::task::spawn_blocking(move || model.embed(KEEPALIVE_TEXT)).await;
+        match result {
+            Ok(Ok(_)) => tracing::debug!("Keepalive embed ran after {:?} idle", activity.idle_for()),
+            Ok(Err(e)) => tracing::warn!("Keepalive embed failed: {}", e),
+            Err(e) => tracing::warn!("Keepalive task failed: {}", e),
+        }
+    }
+}
+
+// Service implementation
+struct LLMServiceImpl {
+    model: Arc<RwLock<EmbeddingModel>>,
+    timeouts: RpcTimeouts,
+    slow_log: SlowLog,
+    activity: Arc<ActivityClock>,
+    load: Arc<LoadGauge>,
+    admission: Arc<Admission>,
+    /// Held for the whole of an InitModel, so loads don't overlap.
+    init_lock: tokio::sync::Mutex<()>,
+    metrics: Arc<metrics::Metrics>,
+    /// Set when identical concurrent Embed calls share one forward pass.
+    coalescer: Option<coalesce::Coalescer<Result<EmbedResponse, Status>>>,
+    /// Set when responses are signed with `SIDECAR_SIGNING_KEY`.
+    signer: Option<signing::Signer>,
+    #[cfg(feature = "upstream")]
+    upstream: Option<Arc<upstream::UpstreamClient>>,
+    #[cfg(feature = "shm")]
+    shm: Arc<shm::ShmWriter>,
+}
+
+impl Default for LLMServiceImpl {
+    fn default() -> Self {
+        Self {
+            model: Arc::new(RwLock::new(EmbeddingModel::new())),
+            timeouts: RpcTimeouts::default(),
+            slow_log: SlowLog::default(),
+            activity: Arc::new(ActivityClock::new()),
+            load: Arc::new(LoadGauge::default()),
+            admission: Arc::new(Admission::default()),
+            init_lock: tokio::sync::Mutex::new(()),
+            metrics: Arc::new(metrics::Metrics::default()),
+            coalescer: None,
+            signer: None,
+            #[cfg(feature = "upstream")]
+            upstream: None,
+            #[cfg(feature = "shm")]
+            shm: Arc::new(shm::ShmWriter::from_env()),
+        }
+    }
+}
+
+/// Whether an Embed result may be shared with coalesced callers. Running out
+/// of time or being cancelled is the leading call's own outcome, not one of
+/// the request, so those callers run the request themselves instead.
+fn coalescable(result: &Result<EmbedResponse, Status>) -> bool {
+    !matches!(result, Err(status) if matches!(status.code(), tonic::Code::DeadlineExceeded | tonic::Code::Cancelled))
+}
+
+/// Metadata key set on the `expected_dim` guard's failed_precondition. That
+/// failure is about the client's index rather than this replica, so it is
+/// returned as is instead of being forwarded upstream.
+const DIM_MISMATCH_KEY: &str = "x-sidecar-dim-mismatch";
+
+/// Local failures an upstream might not share. Bad input stays bad anywhere.
+#[cfg(feature = "upstream")]
+fn should_fall_back(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::FailedPrecondition | tonic::Code::Internal | tonic::Code::ResourceExhausted
+    ) && status.metadata().get(DIM_MISMATCH_KEY).is_none()
+}
+
+impl LLMServiceImpl {
+    /// Retry a failed local embed against the upstream, when one is configured.
+    async fn embed_fallback(&self, _req: EmbedRequest, local: Status) -> Result<EmbedResponse, Status> {
+        #[cfg(feature = "upstream")]
+        if let Some(upstream) = self.upstream.as_ref().filter(|_| should_fall_back(&local)) {
+            tracing::warn!("Local embed failed ({}), falling back to upstream", local.message());
+            return upstream.embed(_req).await;
+        }
+        Err(local)
+    }
+
+    /// Batch counterpart of [`Self::embed_fallback`].
+    async fn batch_embed_fallback(&self, _req: BatchEmbedRequest, local: Status) -> Result<BatchEmbedResponse, Status> {
+        #[cfg(feature = "upstream")]
+        if let Some(upstream) = self.upstream.as_ref().filter(|_| should_fall_back(&local)) {
+            tracing::warn!("Local batch embed failed ({}), falling back to upstream", local.message());
+            return upstream.batch_embed(_req).await;
+        }
+        Err(local)
+    }
+
+    /// `response` with its signature set, when signing is enabled.
+    fn signed(&self, mut response: EmbedResponse) -> EmbedResponse {
+        if let Some(signer) = &self.signer {
+            response.signature = signer.sign(&le_bytes(&response.vector), &response.model_fingerprint);
+        }
+        response
+    }
+
+    /// The Embed rpc, wrapped by [`LlmService::embed`] for request and error
+    /// counting, coalescing and load metadata.
+    async fn embed_counted(&self, request: Request<EmbedRequest>) -> Result<EmbedResponse, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let mut req = request.into_inner();
+        if req.clip_value < 0.0 || !(0.0..100.0).contains(&req.clip_percentile) {
+            return Err(Status::invalid_argument(
+                "clip_value must be non-negative and clip_percentile within [0, 100)",
+            ));
+        }
+        if req.clip_value > 0.0 && req.clip_percentile > 0.0 {
+            return Err(Status::invalid_argument("clip_value and clip_percentile cannot be combined"));
+        }
+        if !req.segment_marker.is_empty() {
+            if req.segment_marker.trim().is_empty() {
+                return Err(Status::invalid_argument("segment_marker must not be blank"));
+            }
+            if req.best_window || req.pad_short_below > 0 {
+                return Err(Status::invalid_argument(
+                    "segment_marker cannot be combined with best_window or pad_short_below",
+                ));
+            }
+        }
+        if req.char_start.is_some() || req.char_end.is_some() {
+            req.text = char_span(&req.text, req.char_start, req.char_end)?.to_string();
+        }
+        // Checked before any prefix is added. Blank input would embed as just
+        // the special tokens, a vector that matches nothing meaningfully.
+        if req.text.trim().is_empty() {
+            return Err(Status::invalid_argument("text must not be empty"));
+        }
+
+        let fallback_req = req.clone();
+        let (slow_log, started) = (self.slow_log, std::time::Instant::now());
+        let metrics = self.metrics.clone();
+        let result = self.with_model(timeout, move |model| {
+            let queued = started.elapsed();
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+            let (model, variant) = model.route(&req.routing_key);
+            let normalize = req.normalize.unwrap_or(model.normalize);
+            if req.return_raw && !normalize {
+                return Err(Status::invalid_argument(
+                    "return_raw only applies when normalize is set; vector is already unnormalized",
+                ));
+            }
+            let sanitized = model.invalid_text_policy.apply(&mut req.text)?;
+            if sanitized && req.text.trim().is_empty() {
+                return Err(Status::invalid_argument(
+                    "text is empty once its U+FFFD replacement characters are removed",
+                ));
+            }
+            let mut repeated_short_input = false;
+            if req.pad_short_below > 0 && !req.best_window {
+                if let Some(repeated) = model
+                    .repeat_short(&req.text, req.pad_short_below as usize)
+                    .map_err(embed_error_status)?
+                {
+                    req.text = repeated;
+                    repeated_short_input = true;
+                }
+            }
+            // After padding, so the prefix appears once rather than per copy.
+            let prefix = match &req.prefix {
+                Some(prefix) => prefix.as_str(),
+                None => model.prefix_for(&req.input_type)?,
+            };
+            req.text.insert_str(0, prefix);
+            if !req.window_query.is_empty() && req.window_query.len() != model.embedding_dim {
+                return Err(Status::invalid_argument(format!(
+                    "window_query has {} dimensions but the model produces {}",
+                    req.window_query.len(),
+                    model.embedding_dim
+                )));
+            }
+
+            let run = |model: &EmbeddingModel| {
+                if req.best_window {
+                    model
+                        .embed_best_window(&req.text, &req.window_query)
+                        .map(|(vector, windows, tokens)| (vector, windows, false, tokens))
+                } else if !req.segment_marker.is_empty() {
+                    model
+                        .embed_structured(&req.text, &req.segment_marker)
+                        .map(|(vector, truncated, tokens)| (vector, 1, truncated, tokens))
+                } else {
+                    model
+                        .embed_with_policy(&req.text)
+                        .map(|(vector, truncated, tokens)| (vector, 1, truncated, tokens))
+                }
+            };
+            // Bad input fails the same way on any model; only inference
+            // failures are retried on the fallback. A window_query sized for
+            // the primary can't be scored against a fallback of another width.
+            let inference = std::time::Instant::now();
+            let ((embedded, used), phases) = timing::measure(req.timing, || {
+                match (run(model), model.fallback.as_deref()) {
+                    (Err(e), Some(fallback))
+                        if e.downcast_ref::<InvalidInput>().is_none()
+                            && (req.window_query.is_empty() || fallback.embedding_dim == model.embedding_dim) =>
+                    {
+                        tracing::warn!("Primary model failed, retrying on fallback: {:#}", e);
+                        (run(fallback), fallback)
+                    }
+                    (result, _) => (result, &*model),
+                }
+            });
+            metrics.observe_inference(inference.elapsed());
+            let postprocess = std::time::Instant::now();
+            let (mut vector, windows_evaluated, was_truncated, token_count) = embedded.map_err(embed_error_status)?;
+
+            // Post-processing runs in a fixed order, after pooling and any
+            // projection: clip, normalize, MIPS augmentation, then the LSH
+            // code of the final vector.
+            if req.clip_value > 0.0 {
+                clip_components(&mut vector, req.clip_value);
+            } else if req.clip_percentile > 0.0 {
+                clip_components(&mut vector, abs_percentile(&vector, req.clip_percentile));
+            }
+            let unnormalized_vector = if req.return_raw { vector.clone() } else { Vec::new() };
+            if normalize {
+                l2_normalize(&mut vector);
+            }
+            if !req.mips_role.is_empty() {
+                // Normalized documents have unit norm, so M defaults to 1 there.
+                let max_norm = match req.mips_max_norm {
+                    m if m > 0.0 => m,
+                    _ if normalize || req.mips_role == "query" => 1.0,
+                    _ => {
+                        return Err(Status::invalid_argument(
+                            "mips_max_norm is required for unnormalized documents",
+                        ))
+                    }
+                };
+                mips_augment(&mut vector, &req.mips_role, max_norm)?;
+            }
+            let lsh_code = match req.lsh_bits {
+                0 => Vec::new(),
+                bits @ 1..=MAX_LSH_BITS => lsh_code(&vector, bits as usize, req.lsh_seed).map_err(embed_error_status)?,
+                bits => {
+                    return Err(Status::invalid_argument(format!(
+                        "lsh_bits must be within 1..={}, got {}",
+                        MAX_LSH_BITS, bits
+                    )))
+                }
+            };
+            // Checked on the output so a fallback of another width, or the
+            // MIPS coordinate, is accounted for.
+            if req.expected_dim > 0 && vector.len() != req.expected_dim as usize {
+                let mut metadata = tonic::metadata::MetadataMap::new();
+                metadata.insert(DIM_MISMATCH_KEY, tonic::metadata::MetadataValue::from_static("1"));
+                return Err(Status::with_metadata(
+                    tonic::Code::FailedPrecondition,
+                    format!(
+                        "Model {} produces {}-dimensional vectors, but the client expects {}",
+                        used.fingerprint,
+                        vector.len(),
+                        req.expected_dim
+                    ),
+                    metadata,
+                ));
+            }
+            slow_log.check("embed", started, model, &[&req.text]);
+            Ok(EmbedResponse {
+                dim: vector.len() as i32,
+                raw_vector: le_bytes(&vector),
+                stats: req.sparsity_threshold.map(|threshold| vector_stats(&vector, threshold)),
+                vector,
+                unnormalized_vector,
+                windows_evaluated: windows_evaluated as i32,
+                model_fingerprint: used.fingerprint.clone(),
+                sanitized,
+                used_fallback: !std::ptr::eq(used, model),
+                repeated_short_input,
+                lsh_code,
+                lsh_bits: req.lsh_bits,
+                was_truncated,
+                token_count: token_count as i32,
+                variant: variant.to_string(),
+                timing: req.timing.then(|| EmbedTiming {
+                    queue_us: queued.as_micros() as i64,
+                    tokenize_us: phases.tokenize.as_micros() as i64,
+                    forward_us: phases.forward.as_micros() as i64,
+                    pool_us: phases.pool.as_micros() as i64,
+                    postprocess_us: postprocess.elapsed().as_micros() as i64,
+                    total_us: started.elapsed().as_micros() as i64,
+                }),
+                provenance: req.debug.then(|| EmbeddingProvenance {
+                    sidecar_version: env!("CARGO_PKG_VERSION").to_string(),
+                    candle_version: env!("SIDECAR_CANDLE_VERSION").to_string(),
+                    tokenizers_version: env!("SIDECAR_TOKENIZERS_VERSION").to_string(),
+                    model_path: used.model_path.clone(),
+                    model_fingerprint: used.fingerprint.clone(),
+                    device: device_label(&used.device).to_string(),
+                    pooling: used.pooling.label().to_string(),
+                    exclude_special_tokens: used.exclude_special_tokens,
+                    normalized: normalize,
+                }),
+            })
+        })
+        .await;
+
+        match result {
+            Ok(response) => Ok(response),
+            Err(status) => self.embed_fallback(fallback_req, status).await,
+        }
+    }
+
+    /// Run `work` against the model on the blocking pool so inference and
+    /// downloads don't stall the runtime, failing with `deadline_exceeded` once
+    /// `limit` elapses. The limit covers waiting for the model lock too. Work
+    /// already running on the blocking pool finishes in the background; only the
+    /// caller stops waiting for it. Takes a read lock, so any number of these
+    /// run concurrently.
+    async fn with_model<T, F>(&self, limit: Option<Duration>, work: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&EmbeddingModel) -> Result<T, Status> + Send + 'static,
+    {
+        let model = self.model.clone();
+        self.run_locked(limit, model.read_owned(), move |model| work(&model)).await
+    }
+
+    /// [`Self::with_model`] under the write lock, for work that replaces the
+    /// model or its corpus and labels.
+    async fn with_model_mut<T, F>(&self, limit: Option<Duration>, work: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut EmbeddingModel) -> Result<T, Status> + Send + 'static,
+    {
+        let model = self.model.clone();
+        self.run_locked(limit, model.write_owned(), move |mut model| work(&mut model)).await
+    }
+
+    /// The future behind [`Self::with_model`], given the lock to wait for. It
+    /// borrows nothing from `self`, so streaming rpcs can spawn it and return
+    /// their receiver straight away.
+    fn run_locked<G, T, F>(
+        &self,
+        limit: Option<Duration>,
+        lock: impl std::future::Future<Output = G> + Send + 'static,
+        work: F,
+    ) -> impl std::future::Future<Output = Result<T, Status>> + Send + 'static
+    where
+        G: Send + 'static,
+        T: Send + 'static,
+        F: FnOnce(G) -> Result<T, Status> + Send + 'static,
+    {
+        self.activity.touch();
+        let (load, admission) = (self.load.clone(), self.admission.clone());
+        let task = async move {
+            let queued = GaugeGuard::enter(&load, |gauge| &gauge.queued);
+            let slot = admission.acquire(&load).await?;
+            let guard = lock.await;
+            drop(queued);
+            let in_flight = GaugeGuard::enter(&load, |gauge| &gauge.in_flight);
+            tokio::task::spawn_blocking(move || {
+                let _in_flight = in_flight;
+                let _slot = slot;
+                work(guard)
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Worker task failed: {}", e)))?
+        };
+
+        async move {
+            match limit {
+                Some(limit) => tokio::time::timeout(limit, task).await.map_err(|_| {
+                    Status::deadline_exceeded(format!("Request exceeded its {} ms deadline", limit.as_millis()))
+                })?,
+                None => task.await,
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl LlmService for LLMServiceImpl {
+    async fn init_model(&self, request: Request<InitRequest>) -> Result<Response<InitResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.init, client_deadline(&request));
+        let req = request.into_inner();
+        let _loading = match timeout {
+            Some(limit) => tokio::time::timeout(limit, self.init_lock.lock())
+                .await
+                .map_err(|_| Status::deadline_exceeded("Timed out waiting for another InitModel to finish"))?,
+            None => self.init_lock.lock().await,
+        };
+
+        let (key, force_reload) = (init_key(&req), req.force_reload);
+        let current = self
+            .with_model(timeout, move |model| {
+                Ok(model.model.is_some() && !force_reload && model.init_key == key)
+            })
+            .await?;
+        if current {
+            tracing::info!("Model {} already loaded with identical settings, skipping reload", req.model_path);
+            return Ok(Response::new(InitResponse {
+                success: true,
+                message: format!("Embedding model from {} already loaded (reload skipped)", req.model_path),
+            }));
+        }
+
+        let load_req = req.clone();
+        let loaded = match ReloadMode::parse(&req.reload_mode) {
+            Err(e) => Err(e),
+            Ok(ReloadMode::Swap) => {
+                // Requests keep running on the current model while this loads;
+                // the swap itself waits only for the ones already holding it.
+                let fresh = self
+                    .run_locked(timeout, std::future::ready(()), move |()| Ok(EmbeddingModel::loaded(&load_req)))
+                    .await?;
+                match fresh {
+                    Ok(fresh) => {
+                        let old = std::mem::replace(&mut *self.model.write().await, fresh);
+                        // Free the old weights off the runtime threads.
+                        tokio::task::spawn_blocking(move || drop(old));
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Ok(ReloadMode::Replace) => {
+                self.with_model_mut(timeout, move |model| {
+                    *model = EmbeddingModel::new();
+                    Ok(EmbeddingModel::loaded(&load_req).map(|fresh| *model = fresh))
+                })
+                .await?
+            }
+        };
+
+        Ok(Response::new(match loaded {
+            Ok(()) => InitResponse {
+                success: true,
+                message: format!("Embedding model loaded from {}", req.model_path),
+            },
+            Err(e) => InitResponse {
+                success: false,
+                message: format!("Failed to load model: {}", e),
+            },
+        }))
+    }
+
+    async fn unload_model(
+        &self,
+        request: Request<UnloadModelRequest>,
+    ) -> Result<Response<UnloadModelResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.init, client_deadline(&request));
+        // Serialized with InitModel, so an unload can't land between a swap
+        // load finishing and its model being installed.
+        let _loading = match timeout {
+            Some(limit) => tokio::time::timeout(limit, self.init_lock.lock())
+                .await
+                .map_err(|_| Status::deadline_exceeded("Timed out waiting for an InitModel to finish"))?,
+            None => self.init_lock.lock().await,
+        };
+
+        self.with_model_mut(timeout, move |model| {
+            if model.model.is_none() {
+                return Ok(UnloadModelResponse {
+                    success: true,
+                    message: "No model loaded".to_string(),
+                });
+            }
+            let model_path = std::mem::take(&mut model.model_path);
+            // Dropping the old state frees the weights, tokenizer, fallback,
+            // corpus and labels in one go.
+            *model = EmbeddingModel::new();
+            tracing::info!("Unloaded model {}", model_path);
+            Ok(UnloadModelResponse {
+                success: true,
+                message: format!("Embedding model from {} unloaded", model_path),
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    type GenerateStream = tokio_stream::wrappers::ReceiverStream<Result<GenerateResponse, Status>>;
+
+    async fn generate(&self, request: Request<GenerateRequest>) -> Result<Response<Self::GenerateStream>, Status> {
+        let timeout = effective_timeout(self.timeouts.generate, client_deadline(&request));
+        let req = request.into_inner();
+        if req.prompt.trim().is_empty() {
+            return Err(Status::invalid_argument("prompt must not be empty"));
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+
+        let prompt = req.prompt;
+        let embed_prompt = prompt.clone();
+        let (slow_log, started) = (self.slow_log, std::time::Instant::now());
+        let embedding_result = self
+            .with_model(timeout, move |model| {
+                if model.model.is_none() {
+                    return Err(Status::failed_precondition("Model not initialized"));
+                }
+                let embedding = model.embed(&embed_prompt);
+                slow_log.check("generate", started, model, &[&embed_prompt]);
+                Ok(embedding)
+            })
+            .await;
+
+        tokio::spawn(async move {
+            let embedding_result = match embedding_result {
+                Ok(result) => result,
+                Err(status) => {
+                    let _ = tx.send(Err(status)).await;
+                    return;
+                }
+            };
+
+            match embedding_result {
+                Ok(embedding) => {
+                    if embedding.is_empty() {
+                        let _ = tx.send(Err(Status::internal("Failed to generate embedding"))).await;
+                        return;
+                    }
+
+                    // Show embedding info
+                    let non_zero_count = embedding.iter().filter(|&&x| x.abs() > 1e-6).count();
+                    let max_val = embedding.iter().fold(0.0f32, |a, &b| a.max(b.abs()));
+                    let min_val = embedding.iter().fold(0.0f32, |a, &b| a.min(b.abs()));
+
+                    let header = format!(
+                        "Embedding generated for: '{}'\nDim: {} | Non-zero: {} | Range: [{:.4}, {:.4}]\n\nVector (hex):\n",
+                        prompt,
+                        embedding.len(),
+                        non_zero_count,
+                        min_val,
+                        max_val
+                    );
+
+                    // Send header
+                    for ch in header.chars() {
+                        if tx.send(Ok(GenerateResponse {
+                            text: ch.to_string(),
+                            done: false,
+                            tokens_generated: 0,
+                        })).await.is_err() {
+                            return;
+                        }
+                        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                    }
+
+                    // Send embedding vector in hex format (8 values per line)
+                    for (i, val) in embedding.iter().enumerate() {
+                        let hex_val = format!("{:08x}", val.to_bits());
+                        let comma = if (i + 1) % 8 == 0 { "\n" } else { " " };
+                        let text = format!("{}{}", hex_val, comma);
+
+                        if tx.send(Ok(GenerateResponse {
+                            text,
+                            done: false,
+                            tokens_generated: i as i32,
+                        })).await.is_err() {
+                            return;
+                        }
+                        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                    }
+
+                    // Send done signal
+                    let _ = tx.send(Ok(GenerateResponse {
+                        text: String::new(),
+                        done: true,
+                        tokens_generated: embedding.len() as i32,
+                    })).await;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(embed_error_status(e))).await;
+                }
+            }
+        });
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    async fn embed(&self, request: Request<EmbedRequest>) -> Result<Response<EmbedResponse>, Status> {
+        self.metrics.embed_request();
+        let result = match &self.coalescer {
+            Some(coalescer) => {
+                // The whole message rather than a hash of it: a collision
+                // would hand one request another's vector.
+                let key = format!("{:?}", request.get_ref());
+                coalescer.run(key, self.embed_counted(request), coalescable).await
+            }
+            None => self.embed_counted(request).await,
+        };
+        if result.is_err() {
+            self.metrics.embed_error();
+        }
+        let mut response = Response::new(self.signed(result?));
+        self.load.annotate(&mut response);
+        Ok(response)
+    }
+
+    async fn batch_embed(&self, request: Request<BatchEmbedRequest>) -> Result<Response<BatchEmbedResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let mut req = request.into_inner();
+        if req.texts.is_empty() {
+            return Err(Status::invalid_argument("texts must not be empty"));
+        }
+        if !req.group_keys.is_empty() && req.group_keys.len() != req.texts.len() {
+            return Err(Status::invalid_argument(format!(
+                "group_keys has {} entries but texts has {}",
+                req.group_keys.len(),
+                req.texts.len()
+            )));
+        }
+        if req.join_with.is_some() && !req.group_keys.is_empty() {
+            return Err(Status::invalid_argument("join_with and group_keys cannot be combined"));
+        }
+        if !req.prefixes.is_empty() && req.prefixes.len() != req.texts.len() {
+            return Err(Status::invalid_argument(format!(
+                "prefixes has {} entries but texts has {}",
+                req.prefixes.len(),
+                req.texts.len()
+            )));
+        }
+        // Blank entries are set aside before embedding; zero_vector puts them
+        // back as zeros afterwards, so they don't pull on group centroids.
+        let empty_input_policy = EmptyInputPolicy::parse(&req.empty_input_policy)?;
+        let empty: Vec<usize> = (0..req.texts.len()).filter(|&i| req.texts[i].trim().is_empty()).collect();
+        if let Some(&first) = empty.first() {
+            match empty_input_policy {
+                EmptyInputPolicy::Error => {
+                    return Err(Status::invalid_argument(format!(
+                        "texts[{}] is empty or whitespace-only (see empty_input_policy)",
+                        first
+                    )))
+                }
+                EmptyInputPolicy::ZeroVector if req.join_with.is_some() => {
+                    return Err(Status::invalid_argument(
+                        "empty_input_policy zero_vector cannot be combined with join_with",
+                    ))
+                }
+                _ if req.join_with.is_some() && empty.len() == req.texts.len() => {
+                    return Err(Status::invalid_argument("every text is empty; nothing to join"))
+                }
+                _ => {}
+            }
+            remove_indices(&mut req.texts, &empty);
+            if !req.group_keys.is_empty() {
+                remove_indices(&mut req.group_keys, &empty);
+            }
+            if !req.prefixes.is_empty() {
+                remove_indices(&mut req.prefixes, &empty);
+            }
+        }
+        #[cfg(not(feature = "shm"))]
+        if req.shm_output {
+            return Err(Status::unimplemented("shm_output requires a build with the shm feature"));
+        }
+        #[cfg(feature = "shm")]
+        let shm_output = req.shm_output;
+
+        let fallback_req = req.clone();
+        let (slow_log, started) = (self.slow_log, std::time::Instant::now());
+        let result = self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+            if req.prefixes.is_empty() {
+                let prefix = model.prefix_for(&req.input_type)?;
+                req.texts.iter_mut().for_each(|text| text.insert_str(0, prefix));
+            } else {
+                for (text, prefix) in req.texts.iter_mut().zip(&req.prefixes) {
+                    text.insert_str(0, prefix);
+                }
+            }
+
+            let mut vectors = match &req.join_with {
+                Some(separator) => vec![model.embed_joined(&req.texts, separator).map_err(embed_error_status)?],
+                None if req.texts.is_empty() => Vec::new(),
+                None => model.embed_batch(&req.texts).map_err(embed_error_status)?,
+            };
+            let texts: Vec<&str> = req.texts.iter().map(String::as_str).collect();
+            slow_log.check("batch_embed", started, model, &texts);
+
+            let centroids = if req.group_keys.is_empty() {
+                Vec::new()
+            } else {
+                group_centroids(&req.group_keys, &vectors)
+            };
+            if empty_input_policy == EmptyInputPolicy::ZeroVector {
+                for &index in &empty {
+                    vectors.insert(index, vec![0.0; model.embedding_dim]);
+                }
+            }
+
+            Ok(BatchEmbedResponse {
+                embeddings: vectors.into_iter().map(|vector| Embedding { vector }).collect(),
+                dim: model.embedding_dim as i32,
+                centroids,
+                shm: None,
+                empty_indices: empty.iter().map(|&index| index as i32).collect(),
+            })
+        })
+        .await;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(status) => self.batch_embed_fallback(fallback_req, status).await?,
+        };
+
+        #[cfg(feature = "shm")]
+        let response = if shm_output {
+            let shm = self.shm.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut response = response;
+                let vectors: Vec<Vec<f32>> = response.embeddings.drain(..).map(|e| e.vector).collect();
+                response.shm = Some(
+                    shm.write(&vectors, response.dim as usize)
+                        .map_err(|e| Status::internal(format!("Shared-memory write failed: {}", e)))?,
+                );
+                Ok::<_, Status>(response)
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Worker task failed: {}", e)))??
+        } else {
+            response
+        };
+        let mut response = Response::new(response);
+        self.load.annotate(&mut response);
+        Ok(response)
+    }
+
+    async fn weighted_embed(&self, request: Request<WeightedEmbedRequest>) -> Result<Response<EmbedResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let req = request.into_inner();
+        if req.texts.is_empty() {
+            return Err(Status::invalid_argument("texts must not be empty"));
+        }
+        if req.weights.len() != req.texts.len() {
+            return Err(Status::invalid_argument(format!(
+                "weights has {} entries but texts has {}",
+                req.weights.len(),
+                req.texts.len()
+            )));
+        }
+        let total: f32 = req.weights.iter().sum();
+        if total == 0.0 || !total.is_finite() {
+            return Err(Status::invalid_argument("weights must have a finite, nonzero sum"));
+        }
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let vectors = model.embed_batches(&req.texts).map_err(embed_error_status)?;
+            let mut vector = weighted_mean(&vectors, &req.weights);
+            if req.normalize {
+                l2_normalize(&mut vector);
+            }
+
+            Ok(EmbedResponse {
+                raw_vector: le_bytes(&vector),
+                vector,
+                dim: model.embedding_dim as i32,
+                windows_evaluated: 1,
+                model_fingerprint: model.fingerprint.clone(),
+                ..Default::default()
+            })
+        })
+        .await
+        .map(|response| Response::new(self.signed(response)))
+    }
+
+    async fn vector_arithmetic(
+        &self,
+        request: Request<VectorArithmeticRequest>,
+    ) -> Result<Response<EmbedResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let req = request.into_inner();
+        if req.terms.is_empty() {
+            return Err(Status::invalid_argument("terms must not be empty"));
+        }
+        if let Some(index) = req.terms.iter().position(|term| !term.coefficient.is_finite()) {
+            return Err(Status::invalid_argument(format!("term {} has a non-finite coefficient", index)));
+        }
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let mut vector = vec![0.0f32; model.embedding_dim];
+            for term in &req.terms {
+                let embedded = model.embed(&term.text).map_err(embed_error_status)?;
+                for (acc, x) in vector.iter_mut().zip(embedded) {
+                    *acc += term.coefficient * x;
+                }
+            }
+            if req.normalize {
+                l2_normalize(&mut vector);
+            }
+
+            Ok(EmbedResponse {
+                raw_vector: le_bytes(&vector),
+                vector,
+                dim: model.embedding_dim as i32,
+                windows_evaluated: 1,
+                model_fingerprint: model.fingerprint.clone(),
+                ..Default::default()
+            })
+        })
+        .await
+        .map(|response| Response::new(self.signed(response)))
+    }
+
+    async fn drift_check(&self, request: Request<DriftCheckRequest>) -> Result<Response<DriftCheckResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let reference = if req.reference.is_empty() { &model.drift_reference } else { &req.reference };
+            if reference.is_empty() {
+                return Err(Status::failed_precondition(
+                    "No drift reference: pass one in the request or InitRequest.drift_reference",
+                ));
+            }
+
+            let canary = [req.canary.as_str(), model.drift_canary.as_str()]
+                .into_iter()
+                .find(|c| !c.is_empty())
+                .unwrap_or(DEFAULT_DRIFT_CANARY);
+            let vector = model.embed(canary).map_err(embed_error_status)?;
+            if vector.len() != reference.len() {
+                return Err(Status::invalid_argument(format!(
+                    "Reference has {} dimensions but the model produces {}",
+                    reference.len(),
+                    vector.len()
+                )));
+            }
+
+            let threshold = if req.threshold > 0.0 { req.threshold } else { DEFAULT_DRIFT_THRESHOLD };
+            let similarity = cosine_similarity(&vector, reference);
+            if similarity < threshold {
+                tracing::warn!("Drift detected: canary similarity {:.4} below threshold {:.4}", similarity, threshold);
+            }
+
+            Ok(DriftCheckResponse {
+                similarity,
+                drifted: similarity < threshold,
+                threshold,
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn classify(&self, request: Request<ClassifyRequest>) -> Result<Response<ClassifyResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+            let labels = match &model.classifier {
+                Some(head) => head.labels.clone(),
+                None => {
+                    return Err(Status::failed_precondition(
+                        "Loaded model has no sequence-classification head",
+                    ))
+                }
+            };
+
+            let logits = model.classify(&req.text).map_err(embed_error_status)?;
+            let probabilities = if req.softmax { softmax(&logits) } else { Vec::new() };
+            Ok(ClassifyResponse {
+                logits,
+                probabilities,
+                labels,
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn token_embed(&self, request: Request<TokenEmbedRequest>) -> Result<Response<TokenEmbedResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let tokens = model.encode(&req.text).map_err(embed_error_status)?;
+            let rows = model.token_embeddings(&tokens).map_err(embed_error_status)?;
+            let special_token_positions = tokens
+                .get_special_tokens_mask()
+                .iter()
+                .enumerate()
+                .filter(|(_, &special)| special == 1)
+                .map(|(position, _)| position as u32)
+                .collect();
+
+            Ok(TokenEmbedResponse {
+                // Token rows are hidden states; the pooled-vector projection
+                // doesn't apply, so report their own width.
+                dim: rows.first().map_or(0, |row| row.len() as i32),
+                token_vectors: rows.into_iter().map(|vector| Embedding { vector }).collect(),
+                tokens: tokens.get_tokens().to_vec(),
+                token_ids: tokens.get_ids().to_vec(),
+                special_token_positions,
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    type StreamTokenEmbedStream = tokio_stream::wrappers::ReceiverStream<Result<TokenEmbedChunk, Status>>;
+
+    async fn stream_token_embed(
+        &self,
+        request: Request<StreamTokenEmbedRequest>,
+    ) -> Result<Response<Self::StreamTokenEmbedStream>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+        let rows_per_chunk = if req.rows_per_chunk > 0 { req.rows_per_chunk as usize } else { DEFAULT_ROWS_PER_CHUNK };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let chunks = tx.clone();
+        // Set once the caller has been sent an error, so rows still coming off
+        // the blocking pool after a timeout aren't streamed behind it.
+        let stopped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let emitting = stopped.clone();
+        let work = self.run_locked(timeout, self.model.clone().read_owned(), move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let tokens = model.encode(&req.text).map_err(embed_error_status)?;
+            let special = tokens.get_special_tokens_mask();
+
+            model
+                .token_embedding_chunks(&tokens, rows_per_chunk, |offset, rows| {
+                    if emitting.load(std::sync::atomic::Ordering::Relaxed) {
+                        return false;
+                    }
+                    let end = offset + rows.len();
+                    let chunk = TokenEmbedChunk {
+                        row_offset: offset as i32,
+                        total_rows: tokens.len() as i32,
+                        dim: rows.first().map_or(0, |row| row.len() as i32),
+                        token_vectors: rows.into_iter().map(|vector| Embedding { vector }).collect(),
+                        tokens: tokens.get_tokens()[offset..end].to_vec(),
+                        token_ids: tokens.get_ids()[offset..end].to_vec(),
+                        special_token_positions: (offset..end)
+                            .filter(|&position| special[position] == 1)
+                            .map(|position| position as u32)
+                            .collect(),
+                    };
+                    chunks.blocking_send(Ok(chunk)).is_ok()
+                })
+                .map_err(embed_error_status)
+        });
+
+        tokio::spawn(async move {
+            if let Err(status) = work.await {
+                stopped.store(true, std::sync::atomic::Ordering::Relaxed);
+                let _ = tx.send(Err(status)).await;
+            }
+        });
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    async fn load_corpus(&self, request: Request<LoadCorpusRequest>) -> Result<Response<LoadCorpusResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let req = request.into_inner();
+        if !req.ids.is_empty() && req.ids.len() != req.texts.len() {
+            return Err(Status::invalid_argument(format!(
+                "ids has {} entries but texts has {}",
+                req.ids.len(),
+                req.texts.len()
+            )));
+        }
+        if !(0.0..=1.0).contains(&req.dedup_threshold) {
+            return Err(Status::invalid_argument("dedup_threshold must be within [0, 1]"));
+        }
+
+        // Embed under the read lock so other requests keep running, then take
+        // the write lock only to install the result.
+        let started = std::time::Instant::now();
+        let (key, entries, deduplicated) = self
+            .with_model(timeout, move |model| {
+                if model.model.is_none() {
+                    return Err(Status::failed_precondition("Model not initialized"));
+                }
+
+                let vectors = model.embed_batches(&req.texts).map_err(embed_error_status)?;
+                let mut entries: Vec<CorpusEntry> = req
+                    .texts
+                    .into_iter()
+                    .zip(vectors)
+                    .enumerate()
+                    .map(|(index, (text, vector))| {
+                        let id = req.ids.get(index).cloned().unwrap_or_else(|| index.to_string());
+                        CorpusEntry { id, text, vector }
+                    })
+                    .collect();
+
+                let total = entries.len();
+                if req.dedup_threshold > 0.0 {
+                    entries = dedup_corpus(entries, req.dedup_threshold);
+                }
+                let deduplicated = total - entries.len();
+                Ok((model.init_key.clone(), entries, deduplicated))
+            })
+            .await?;
+
+        let remaining = timeout.map(|limit| limit.saturating_sub(started.elapsed()));
+        self.with_model_mut(remaining, move |model| {
+            // The corpus is dropped on every load; vectors from the model it
+            // replaced must not come back.
+            if model.model.is_none() || model.init_key != key {
+                return Err(Status::aborted("Model was reloaded while the corpus was embedding; retry"));
+            }
+            tracing::info!("Loaded corpus of {} entries ({} near-duplicates dropped)", entries.len(), deduplicated);
+            model.corpus = entries;
+            Ok(LoadCorpusResponse {
+                loaded: model.corpus.len() as i32,
+                deduplicated: deduplicated as i32,
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn search_corpus(&self, request: Request<SearchCorpusRequest>) -> Result<Response<SearchCorpusResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+        let top_k = if req.top_k > 0 { req.top_k as usize } else { DEFAULT_TOP_K };
+        let metric = Metric::parse(&req.metric)?;
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+            if model.corpus.is_empty() {
+                return Err(Status::failed_precondition("No corpus loaded"));
+            }
+
+            let query = model.embed(&req.query).map_err(embed_error_status)?;
+            let mut hits: Vec<CorpusHit> = model
+                .corpus
+                .iter()
+                .map(|entry| CorpusHit {
+                    id: entry.id.clone(),
+                    text: entry.text.clone(),
+                    score: metric.score(&query, &entry.vector),
+                })
+                .collect();
+            if metric.is_similarity() {
+                hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+            } else {
+                hits.sort_by(|a, b| a.score.total_cmp(&b.score));
+            }
+            hits.truncate(top_k);
+
+            Ok(SearchCorpusResponse { hits })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn span_embed(&self, request: Request<SpanEmbedRequest>) -> Result<Response<SpanEmbedResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+        if req.ranges.is_empty() {
+            return Err(Status::invalid_argument("ranges must not be empty"));
+        }
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let tokens = model.encode(&req.text).map_err(embed_error_status)?;
+            let token_count = tokens.len();
+            let mut ranges = Vec::with_capacity(req.ranges.len());
+            for (index, range) in req.ranges.iter().enumerate() {
+                let (start, end) = (range.token_start as usize, range.token_end as usize);
+                if start >= end || end > token_count {
+                    return Err(Status::invalid_argument(format!(
+                        "range {} is [{}, {}) but the text has {} tokens",
+                        index, start, end, token_count
+                    )));
+                }
+                ranges.push((start, end));
+            }
+
+            let spans = model.span_embeddings(&tokens, &ranges).map_err(embed_error_status)?;
+            Ok(SpanEmbedResponse {
+                // Hidden-state means like TokenEmbed rows, so no projection.
+                dim: spans.first().map_or(0, |span| span.len() as i32),
+                span_vectors: spans.into_iter().map(|vector| Embedding { vector }).collect(),
+                token_count: token_count as i32,
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn rerank(&self, request: Request<RerankRequest>) -> Result<Response<RerankResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let req = request.into_inner();
+        if req.passages.is_empty() {
+            return Err(Status::invalid_argument("passages must not be empty"));
+        }
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+            if model.classifier.is_none() {
+                return Err(Status::failed_precondition(
+                    "Rerank needs a cross-encoder: the loaded model has no sequence-classification head",
+                ));
+            }
+
+            let scores = model.rerank(&req.query, &req.passages).map_err(embed_error_status)?;
+            let order = if req.sort {
+                let mut order: Vec<i32> = (0..scores.len() as i32).collect();
+                order.sort_by(|&a, &b| scores[b as usize].total_cmp(&scores[a as usize]));
+                order
+            } else {
+                Vec::new()
+            };
+            Ok(RerankResponse { scores, order })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn load_labels(&self, request: Request<LoadLabelsRequest>) -> Result<Response<LoadLabelsResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let req = request.into_inner();
+        if req.names.len() != req.descriptions.len() {
+            return Err(Status::invalid_argument(format!(
+                "names has {} entries but descriptions has {}",
+                req.names.len(),
+                req.descriptions.len()
+            )));
+        }
+        if req.names.is_empty() {
+            return Err(Status::invalid_argument("at least one label is required"));
+        }
+
+        // Same as LoadCorpus: embed under the read lock, install under the write lock.
+        let started = std::time::Instant::now();
+        let (key, labels) = self
+            .with_model(timeout, move |model| {
+                if model.model.is_none() {
+                    return Err(Status::failed_precondition("Model not initialized"));
+                }
+
+                let vectors = model.embed_batches(&req.descriptions).map_err(embed_error_status)?;
+                let labels = req
+                    .names
+                    .into_iter()
+                    .zip(req.descriptions)
+                    .zip(vectors)
+                    .map(|((id, text), vector)| CorpusEntry { id, text, vector })
+                    .collect::<Vec<_>>();
+                Ok((model.init_key.clone(), labels))
+            })
+            .await?;
+
+        let remaining = timeout.map(|limit| limit.saturating_sub(started.elapsed()));
+        self.with_model_mut(remaining, move |model| {
+            if model.model.is_none() || model.init_key != key {
+                return Err(Status::aborted("Model was reloaded while the labels were embedding; retry"));
+            }
+            tracing::info!("Loaded {} zero-shot labels", labels.len());
+            model.labels = labels;
+            Ok(LoadLabelsResponse {
+                loaded: model.labels.len() as i32,
+            })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn zero_shot_classify(
+        &self,
+        request: Request<ZeroShotClassifyRequest>,
+    ) -> Result<Response<ZeroShotClassifyResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+        let top_k = if req.top_k > 0 { req.top_k as usize } else { 1 };
+        let temperature = if req.temperature > 0.0 { req.temperature } else { DEFAULT_LABEL_TEMPERATURE };
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+            if model.labels.is_empty() {
+                return Err(Status::failed_precondition("No labels loaded"));
+            }
+
+            let vector = model.embed(&req.text).map_err(embed_error_status)?;
+            let scores: Vec<f32> = model
+                .labels
+                .iter()
+                .map(|label| cosine_similarity(&vector, &label.vector))
+                .collect();
+            // Softmax over every label, before truncating to top_k.
+            let probabilities = if req.softmax {
+                softmax(&scores.iter().map(|score| score / temperature).collect::<Vec<_>>())
+            } else {
+                Vec::new()
+            };
+
+            let mut labels: Vec<LabelScore> = model
+                .labels
+                .iter()
+                .enumerate()
+                .map(|(i, label)| LabelScore {
+                    name: label.id.clone(),
+                    score: scores[i],
+                    probability: probabilities.get(i).copied().unwrap_or(0.0),
+                })
+                .collect();
+            labels.sort_by(|a, b| b.score.total_cmp(&a.score));
+            labels.truncate(top_k);
+
+            Ok(ZeroShotClassifyResponse { labels })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn explain(&self, request: Request<ExplainRequest>) -> Result<Response<ExplainResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+        let req = request.into_inner();
+
+        self.with_model(timeout, move |model| {
+            if model.model.is_none() {
+                return Err(Status::failed_precondition("Model not initialized"));
+            }
+
+            let (similarity, tokens) = model.explain(&req.query, &req.document).map_err(embed_error_status)?;
+            Ok(ExplainResponse { similarity, tokens })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn tokenize_with_offsets(&self, request: Request<TokenizeRequest>) -> Result<Response<TokenizeResponse>, Status> {
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let req = request.into_inner();
+
+        self.with_model(timeout, move |model| {
+            let tokenizer = model
+                .tokenizer()
+                .map_err(|_| Status::failed_precondition("Model not initialized"))?;
+
+            // Character (not byte) offsets, matching EmbedRequest.char_start/char_end.
+            let encoding = tokenizer
+                .encode_char_offsets(req.text.as_str(), true)
+                .map_err(|e| Status::internal(format!("Tokenization failed: {}", e)))?;
+
+            let tokens = encoding
+                .get_tokens()
+                .iter()
+                .zip(encoding.get_ids())
+                .zip(encoding.get_offsets())
+                .zip(encoding.get_special_tokens_mask())
+                .map(|(((token, &id), &(start, end)), &special)| TokenSpan {
+                    token: token.clone(),
+                    id,
+                    char_start: start as u32,
+                    char_end: end as u32,
+                    has_span: special == 0,
+                })
+                .collect();
+
+            Ok(TokenizeResponse { tokens })
+        })
+        .await
+        .map(Response::new)
+    }
+
+    async fn embed_and_store(
+        &self,
+        request: Request<EmbedAndStoreRequest>,
+    ) -> Result<Response<EmbedAndStoreResponse>, Status> {
+        #[cfg(not(feature = "qdrant"))]
+        {
+            let _ = request;
+            Err(Status::unimplemented("EmbedAndStore requires a build with the qdrant feature"))
+        }
+
+        #[cfg(feature = "qdrant")]
+        {
+            let timeout = effective_timeout(self.timeouts.batch, client_deadline(&request));
+            let req = request.into_inner();
+            if !req.ids.is_empty() && req.ids.len() != req.texts.len() {
+                return Err(Status::invalid_argument(format!(
+                    "ids has {} entries but texts has {}",
+                    req.ids.len(),
+                    req.texts.len()
+                )));
+            }
+            let batch_size = if req.batch_size > 0 { req.batch_size as usize } else { DEFAULT_UPSERT_BATCH };
+
+            // Embed under the model lock, then talk to Qdrant without holding it.
+            let texts = req.texts.clone();
+            let (vectors, url, collection) = self
+                .with_model(timeout, move |model| {
+                    if model.model.is_none() {
+                        return Err(Status::failed_precondition("Model not initialized"));
+                    }
+                    if model.qdrant_url.is_empty() || model.qdrant_collection.is_empty() {
+                        return Err(Status::failed_precondition(
+                            "No Qdrant target: set qdrant_url and qdrant_collection in InitRequest",
+                        ));
+                    }
+                    let vectors = model.embed_batches(&texts).map_err(embed_error_status)?;
+                    Ok((vectors, model.qdrant_url.clone(), model.qdrant_collection.clone()))
+                })
+                .await?;
+
+            let ids: Vec<u64> = if req.ids.is_empty() {
+                req.texts
+                    .iter()
+                    .map(|text| u64::from_str_radix(&stable_hash(&[text]), 16).expect("hex hash"))
+                    .collect()
+            } else {
+                req.ids
+            };
+
+            let texts = req.texts;
+            let response = tokio::task::spawn_blocking(move || {
+                let sink = qdrant::QdrantSink::new(&url, &collection);
+                let mut response = EmbedAndStoreResponse::default();
+                for (range, outcome) in sink.upsert_batches(&ids, &vectors, &texts, batch_size) {
+                    match outcome {
+                        Ok(()) => response.upserted_ids.extend_from_slice(&ids[range]),
+                        Err(e) => {
+                            tracing::warn!("Qdrant upsert of points {}..{} failed: {}", range.start, range.end, e);
+                            response.failures.push(UpsertFailure {
+                                first_index: range.start as i32,
+                                count: range.len() as i32,
+                                error: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                response
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Upsert task failed: {}", e)))?;
+
+            Ok(Response::new(response))
+        }
+    }
+
+    async fn model_info(&self, _request: Request<ModelInfoRequest>) -> Result<Response<ModelInfoResponse>, Status> {
+        let model = self.model.read().await;
+        Ok(Response::new(ModelInfoResponse {
+            model_name: if model.model.is_some() {
+                format!("{} (candle {})", model.model_path, model.architecture)
+            } else {
+                "Not loaded".to_string()
+            },
+            vocab_size: model.vocab_size as i32,
+            context_size: model.max_position_embeddings as i32,
+            backend: "candle".to_string(),
+            device: device_label(&model.device).to_string(),
+            model_fingerprint: model.fingerprint.clone(),
+            embedding_dim: if model.model.is_some() { model.embedding_dim as i32 } else { 0 },
+        }))
+    }
+
+    async fn health(&self, request: Request<HealthRequest>) -> Result<Response<HealthResponse>, Status> {
+        if !request.get_ref().deep {
+            let model = self.model.read().await;
+            return Ok(Response::new(HealthResponse {
+                healthy: true,
+                message: if model.model.is_some() {
+                    "Embedding service is healthy (model loaded)".to_string()
+                } else {
+                    "Embedding service is healthy (no model)".to_string()
+                },
+            }));
+        }
+
+        // A probe that can't get the model, or fails inside it, is reported in
+        // the response rather than as an rpc error, so probes read one field.
+        let timeout = effective_timeout(self.timeouts.embed, client_deadline(&request));
+        let probe = self
+            .with_model(timeout, |model| {
+                if model.model.is_none() {
+                    return Err(Status::failed_precondition("no model loaded"));
+                }
+                let vector = model.embed(WARMUP_TEXT).map_err(embed_error_status)?;
+                if vector.is_empty() {
+                    return Err(Status::internal("probe embed returned an empty vector"));
+                }
+                Ok(vector.len())
+            })
+            .await;
+        Ok(Response::new(match probe {
+            Ok(dim) => HealthResponse {
+                healthy: true,
+                message: format!("Embedding service is healthy (probe embed returned {} dimensions)", dim),
+            },
+            Err(status) => {
+                tracing::warn!("Deep health probe failed: {}", status.message());
+                HealthResponse {
+                    healthy: false,
+                    message: format!("Probe embed failed: {}", status.message()),
+                }
+            }
+        }))
+    }
+}
+
+const DEFAULT_ADDR: &str = "[::0]:50051";
+
+/// Address to listen on: `--addr <host:port>` (or `--addr=<host:port>`), else
+/// `SIDECAR_ADDR`, else all interfaces on port 50051.
+fn listen_addr() -> anyhow::Result<std::net::SocketAddr> {
+    let mut args = std::env::args().skip(1);
+    let mut flag = None;
+    while let Some(arg) = args.next() {
+        if arg == "--addr" {
+            flag = Some(args.next().ok_or_else(|| anyhow::anyhow!("--addr needs a value, e.g. --addr 127.0.0.1:50052"))?);
+        } else if let Some(value) = arg.strip_prefix("--addr=") {
+            flag = Some(value.to_string());
+        }
+    }
+
+    let (value, source) = match (flag, std::env::var("SIDECAR_ADDR")) {
+        (Some(value), _) => (value, "--addr"),
+        (None, Ok(value)) if !value.trim().is_empty() => (value, "SIDECAR_ADDR"),
+        _ => (DEFAULT_ADDR.to_string(), "the default"),
+    };
+    value.trim().parse().map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid listen address {:?} from {}: {} (expected host:port, e.g. 0.0.0.0:50051 or [::1]:50052)",
+            value,
+            source,
+            e
+        )
+    })
+}
+
+/// Completes on ctrl-c or, on unix, SIGTERM (what Kubernetes sends before
+/// killing a pod).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("Cannot listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutting down: draining in-flight requests");
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    let addr = listen_addr()?;
+    let llm_service = LLMServiceImpl {
+        timeouts: RpcTimeouts::from_env(),
+        slow_log: SlowLog::from_env(),
+        admission: Arc::new(Admission::from_env()),
+        coalescer: std::env::var("SIDECAR_COALESCE_EMBEDS")
+            .is_ok_and(|v| v == "1" || v == "true")
+            .then(coalesce::Coalescer::default),
+        signer: signing::Signer::from_env(),
+        #[cfg(feature = "upstream")]
+        upstream: upstream::UpstreamClient::from_env()?.map(Arc::new),
+        ..LLMServiceImpl::default()
+    };
+
+    if let Some(slots) = &llm_service.admission.slots {
+        tracing::info!(
+            "Admission limit: {} requests in flight, queue {}",
+            slots.available_permits(),
+            llm_service.admission.max_queued.map_or("unbounded".to_string(), |max| max.to_string())
+        );
+    }
+
+    if llm_service.signer.is_some() {
+        tracing::info!("Signing embedding responses with SIDECAR_SIGNING_KEY");
+    }
+
+    #[cfg(feature = "upstream")]
+    if let Some(upstream) = llm_service.upstream.clone() {
+        tokio::spawn(async move { upstream.run_health_checks().await });
+    }
+
+    if let Some(interval) = env_millis("SIDECAR_KEEPALIVE_INTERVAL_MS") {
+        tracing::info!("Keepalive enabled: embedding after {:?} idle", interval);
+        tokio::spawn(run_keepalive(llm_service.model.clone(), llm_service.activity.clone(), interval));
+    }
+
+    let metrics_addr = match std::env::var("SIDECAR_METRICS_ADDR") {
+        Ok(value) => {
+            let metrics_addr: std::net::SocketAddr = value
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid SIDECAR_METRICS_ADDR {:?}: {}", value, e))?;
+            tracing::info!("Serving Prometheus metrics on http://{}/metrics", metrics_addr);
+            Some(metrics_addr)
+        }
+        Err(_) => None,
+    };
+    let metrics = llm_service.metrics.clone();
+    let metrics_server = async move {
+        match metrics_addr {
+            Some(addr) => metrics::serve(metrics, addr).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tracing::info!("LLM Embedding Sidecar listening on {}", addr);
+    tracing::info!("Using candle for real BERT embedding models");
+
+    let limits = connection::ConnectionLimits::from_env();
+    tracing::info!(
+        "Connection limits: {} connections, {} concurrent requests each, idle timeout {:?}",
+        limits.max_connections,
+        limits.max_streams_per_connection,
+        limits.idle_timeout
+    );
+    let mut server = Server::builder()
+        .concurrency_limit_per_connection(limits.max_streams_per_connection)
+        .max_concurrent_streams(limits.max_streams_per_connection as u32)
+        .http2_keepalive_interval(limits.http2_keepalive);
+    if let Some(timeout) = limits.request_timeout {
+        server = server.timeout(timeout);
+    }
+    let router = server.add_service(LlmServiceServer::new(llm_service));
+    // The metrics listener only returns on failure (e.g. its port is taken),
+    // which takes the sidecar down with it rather than leaving it unscraped.
+    tokio::select! {
+        served = connection::serve_limited(router, addr, &limits, shutdown_signal()) => served?,
+        metrics = metrics_server => metrics.map_err(|e| anyhow::anyhow!("Metrics server failed: {}", e))?,
+    }
+    tracing::info!("Shutdown complete");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Vocabulary of the test tokenizer; a token's id is its index.
+    const VOCAB: &[&str] = &["[PAD]", "[CLS]", "[SEP]", "[UNK]", "alpha", "beta", "gamma", "delta"];
+    const HIDDEN: usize = 4;
+    const CONTEXT: usize = 8;
+
+    /// The test encoder's hidden state for token `id`, whatever its position.
+    fn row(id: u32) -> Vec<f32> {
+        vec![id as f32, 1.0, (id % 2) as f32, -(id as f32)]
+    }
+
+    /// Mean of the rows of `ids`: what mean pooling over them should return.
+    fn mean_of(ids: &[u32]) -> Vec<f32> {
+        (0..HIDDEN)
+            .map(|i| ids.iter().map(|&id| row(id)[i]).sum::<f32>() / ids.len() as f32)
+            .collect()
+    }
+
+    /// Pooling divides through candle's kernels, so compare to within rounding.
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} vs {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= 1e-5 * e.abs().max(1.0), "{:?} vs {:?}", actual, expected);
+        }
+    }
+
+    /// Encoder looking each token's hidden state up in a fixed table (see
+    /// [`row`]), so pooled vectors are easy to predict. Counts its forward
+    /// passes and sleeps `delay` in each, to make concurrent calls overlap.
+    struct TableEncoder {
+        table: Tensor,
+        forwards: Arc<AtomicUsize>,
+        delay: Duration,
+    }
+
+    impl encoder::Embed for TableEncoder {
+        fn forward(&self, input_ids: &Tensor, _attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+            self.forwards.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(self.delay);
+            let (batch, seq) = input_ids.dims2()?;
+            self.table.index_select(&input_ids.flatten_all()?, 0)?.reshape((batch, seq, HIDDEN))
+        }
+    }
+
+    /// A whitespace-split word-level tokenizer over [`VOCAB`] that frames
+    /// inputs as `[CLS] ... [SEP]`, like a BERT tokenizer.
+    fn test_tokenizer() -> Tokenizer {
+        let special = |id: u32, content: &str| {
+            serde_json::json!({
+                "id": id, "content": content, "single_word": false, "lstrip": false,
+                "rstrip": false, "normalized": false, "special": true
+            })
+        };
+        let vocab: serde_json::Map<String, serde_json::Value> =
+            VOCAB.iter().enumerate().map(|(id, token)| (token.to_string(), id.into())).collect();
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [special(0, "[PAD]"), special(1, "[CLS]"), special(2, "[SEP]")],
+            "normalizer": null,
+            "pre_tokenizer": { "type": "WhitespaceSplit" },
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [
+                    { "SpecialToken": { "id": "[CLS]", "type_id": 0 } },
+                    { "Sequence": { "id": "A", "type_id": 0 } },
+                    { "SpecialToken": { "id": "[SEP]", "type_id": 0 } }
+                ],
+                "pair": [
+                    { "SpecialToken": { "id": "[CLS]", "type_id": 0 } },
+                    { "Sequence": { "id": "A", "type_id": 0 } },
+                    { "SpecialToken": { "id": "[SEP]", "type_id": 0 } },
+                    { "Sequence": { "id": "B", "type_id": 1 } },
+                    { "SpecialToken": { "id": "[SEP]", "type_id": 1 } }
+                ],
+                "special_tokens": {
+                    "[CLS]": { "id": "[CLS]", "ids": [1], "tokens": ["[CLS]"] },
+                    "[SEP]": { "id": "[SEP]", "ids": [2], "tokens": ["[SEP]"] }
+                }
+            },
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" }
+        });
+        Tokenizer::from_str(&json.to_string()).unwrap()
+    }
+
+    /// A loaded-looking model over [`TableEncoder`] and [`test_tokenizer`],
+    /// truncating at `CONTEXT` tokens, and its forward-pass counter.
+    fn test_model_with(delay: Duration) -> (EmbeddingModel, Arc<AtomicUsize>) {
+        let forwards = Arc::new(AtomicUsize::new(0));
+        let rows: Vec<f32> = (0..VOCAB.len() as u32).flat_map(row).collect();
+        let table = Tensor::from_vec(rows, (VOCAB.len(), HIDDEN), &Device::Cpu).unwrap();
+        let mut tokenizer = test_tokenizer();
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: CONTEXT,
+                ..TruncationParams::default()
+            }))
+            .unwrap();
+
+        let mut model = EmbeddingModel::new();
+        model.model = Some(Box::new(TableEncoder {
+            table,
+            forwards: forwards.clone(),
+            delay,
+        }));
+        model.tokenizer = Some(tokenizer);
+        model.embedding_dim = HIDDEN;
+        model.architecture = "bert";
+        model.vocab_size = VOCAB.len();
+        model.max_position_embeddings = CONTEXT;
+        model.special_token_ids = [0, 1, 2].into();
+        model.fingerprint = "test-fingerprint".to_string();
+        (model, forwards)
+    }
+
+    /// A directory under the system temp dir, removed when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!("sidecar-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst));
+            let path = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A tiny randomly initialised BERT checkpoint over [`test_tokenizer`]'s
+    /// vocabulary, with `overrides` merged into its config.json. `heads` adds
+    /// pooler and classifier weights.
+    fn checkpoint(overrides: serde_json::Value, heads: bool) -> TempDir {
+        let mut config = serde_json::json!({
+            "model_type": "bert", "vocab_size": VOCAB.len(), "hidden_size": 8, "num_hidden_layers": 1,
+            "num_attention_heads": 2, "intermediate_size": 16, "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0, "max_position_embeddings": 16, "type_vocab_size": 2,
+            "initializer_range": 0.02, "layer_norm_eps": 1e-12, "pad_token_id": 0
+        });
+        for (key, value) in overrides.as_object().unwrap() {
+            config[key.as_str()] = value.clone();
+        }
+
+        let varmap = candle_nn::VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let bert_config: candle_transformers::models::bert::Config = serde_json::from_value(config.clone()).unwrap();
+        candle_transformers::models::bert::BertModel::load(vb.clone(), &bert_config).unwrap();
+        if heads {
+            ClassificationHead::load(&vb, bert_config.hidden_size, &config).unwrap();
+        }
+
+        let dir = TempDir::new();
+        varmap.save(dir.0.join("model.safetensors")).unwrap();
+        std::fs::write(dir.0.join("config.json"), config.to_string()).unwrap();
+        test_tokenizer().save(dir.0.join("tokenizer.json"), false).unwrap();
+        dir
+    }
+
+    fn init_request(dir: &TempDir) -> InitRequest {
+        InitRequest {
+            model_path: dir.path().to_string(),
+            ..InitRequest::default()
+        }
+    }
+
+    fn test_model() -> EmbeddingModel {
+        test_model_with(Duration::ZERO).0
+    }
+
+    fn service(model: EmbeddingModel) -> LLMServiceImpl {
+        LLMServiceImpl {
+            model: Arc::new(RwLock::new(model)),
+            ..LLMServiceImpl::default()
+        }
+    }
+
+    fn embed_request(text: &str) -> EmbedRequest {
+        EmbedRequest {
+            text: text.to_string(),
+            ..EmbedRequest::default()
+        }
+    }
+
+    /// Start `requests` on `service` at 5 ms intervals and collect the results
+    /// in order.
+    async fn embed_concurrently(
+        service: &Arc<LLMServiceImpl>,
+        requests: Vec<Request<EmbedRequest>>,
+    ) -> Vec<Result<EmbedResponse, Status>> {
+        let mut calls = Vec::new();
+        for request in requests {
+            let service = service.clone();
+            calls.push(tokio::spawn(async move {
+                service.embed(request).await.map(Response::into_inner)
+            }));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mut results = Vec::new();
+        for call in calls {
+            results.push(call.await.unwrap());
+        }
+        results
+    }
+
+    #[test]
+    fn test_model_mean_pools_content_and_special_tokens() {
+        // "alpha beta" is [CLS] alpha beta [SEP].
+        assert_close(&test_model().embed("alpha beta").unwrap(), &mean_of(&[1, 4, 5, 2]));
+    }
+
+    #[tokio::test]
+    async fn coalesced_identical_embeds_run_the_model_once() {
+        let (model, forwards) = test_model_with(Duration::from_millis(100));
+        let service = Arc::new(LLMServiceImpl {
+            coalescer: Some(coalesce::Coalescer::default()),
+            ..service(model)
+        });
+        let requests = (0..8).map(|_| Request::new(embed_request("alpha beta"))).collect();
+
+        let results = embed_concurrently(&service, requests).await;
+
+        assert_eq!(forwards.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_close(&result.unwrap().vector, &mean_of(&[1, 4, 5, 2]));
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesced_embed_does_not_inherit_a_deadline_exceeded() {
+        let (model, forwards) = test_model_with(Duration::from_millis(100));
+        let service = Arc::new(LLMServiceImpl {
+            coalescer: Some(coalesce::Coalescer::default()),
+            ..service(model)
+        });
+        let mut hurried = Request::new(embed_request("alpha beta"));
+        hurried.metadata_mut().insert("grpc-timeout", "20m".parse().unwrap());
+        let patient = Request::new(embed_request("alpha beta"));
+
+        let results = embed_concurrently(&service, vec![hurried, patient]).await;
+
+        assert_eq!(results[0].as_ref().unwrap_err().code(), tonic::Code::DeadlineExceeded);
+        assert_close(&results[1].as_ref().unwrap().vector, &mean_of(&[1, 4, 5, 2]));
+        assert_eq!(forwards.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn fingerprint_changes_with_every_output_setting() {
+        let req = InitRequest::default();
+        let base = EmbeddingModel::new();
+        let fingerprint = base.output_fingerprint(&req, 1000);
+        assert_eq!(fingerprint, EmbeddingModel::new().output_fingerprint(&req, 1000));
+
+        let changes: Vec<(&str, fn(&mut EmbeddingModel))> = vec![
+            ("context", |m| m.max_position_embeddings = 256),
+            ("normalize", |m| m.normalize = true),
+            ("query_prefix", |m| m.query_prefix = "query: ".to_string()),
+            ("passage_prefix", |m| m.passage_prefix = "passage: ".to_string()),
+            ("truncation_policy", |m| m.truncation_policy = TruncationPolicy::Error),
+            ("sequence_mismatch", |m| m.sequence_mismatch = SequenceMismatch::PoolAll),
+            ("architecture", |m| m.architecture = "distilbert"),
+            ("dtype", |m| m.model_dtype = DType::F16),
+            ("pooling", |m| m.pooling = Pooling::Cls),
+            ("invalid_text_policy", |m| m.invalid_text_policy = InvalidTextPolicy::Sanitize),
+            ("exclude_special_tokens", |m| m.exclude_special_tokens = true),
+            ("embedding_dim", |m| m.embedding_dim = 128),
+            ("pooling_ignore_ids", |m| m.pooling_ignore_ids = [7].into()),
+        ];
+        for (setting, change) in changes {
+            let mut model = EmbeddingModel::new();
+            change(&mut model);
+            assert_ne!(model.output_fingerprint(&req, 1000), fingerprint, "{} not fingerprinted", setting);
+        }
+
+        assert_ne!(base.output_fingerprint(&req, 2000), fingerprint, "weights size not fingerprinted");
+        let revised = InitRequest {
+            revision: "v2".to_string(),
+            ..InitRequest::default()
+        };
+        assert_ne!(base.output_fingerprint(&revised, 1000), fingerprint, "revision not fingerprinted");
+        let cased = InitRequest {
+            disable_lowercase: true,
+            ..InitRequest::default()
+        };
+        assert_ne!(base.output_fingerprint(&cased, 1000), fingerprint, "disable_lowercase not fingerprinted");
+    }
+
+    #[test]
+    fn fingerprint_ignores_settings_that_leave_vectors_alone() {
+        let req = InitRequest::default();
+        let fingerprint = EmbeddingModel::new().output_fingerprint(&req, 1000);
+        let mut model = EmbeddingModel::new();
+        model.embed_cache = Some(cache::LruCache::new(16));
+        model.qdrant_url = "http://localhost:6333".to_string();
+        model.drift_canary = "canary".to_string();
+        let tuned = InitRequest {
+            weights_loading: "buffered".to_string(),
+            reload_mode: "replace".to_string(),
+            embed_cache_size: 16,
+            ..InitRequest::default()
+        };
+        assert_eq!(model.output_fingerprint(&tuned, 1000), fingerprint);
+    }
+
+    #[test]
+    fn all_zero_attention_mask_is_invalid_input() {
+        let error = test_model().embed_tokens(&[1, 4, 5, 2], &[0, 0, 0, 0]).unwrap_err();
+        assert!(error.downcast_ref::<InvalidInput>().is_some(), "{}", error);
+        assert_eq!(embed_error_status(error).code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn batch_entry_with_nothing_to_pool_is_invalid_input() {
+        let mut model = test_model();
+        // "alpha" is [CLS] alpha [SEP]; ignore all three.
+        model.pooling_ignore_ids = [1, 2, 4].into();
+        let error = model.embed_batch(&["beta".to_string(), "alpha".to_string()]).unwrap_err();
+        let invalid = error.downcast_ref::<InvalidInput>().expect("InvalidInput");
+        assert!(invalid.0.starts_with("text 1:"), "{}", invalid);
+    }
+
+    #[test]
+    fn partial_mask_pools_only_attended_positions() {
+        let vector = test_model().embed_tokens(&[1, 4, 5, 2], &[1, 1, 0, 1]).unwrap();
+        assert_close(&vector, &mean_of(&[1, 4, 2]));
+        assert!(vector.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn group_centroids_average_each_group_in_first_seen_order() {
+        let keys: Vec<String> = ["b", "a", "b"].iter().map(|k| k.to_string()).collect();
+        let vectors = vec![vec![1.0, 0.0], vec![5.0, 5.0], vec![3.0, 2.0]];
+        let centroids = group_centroids(&keys, &vectors);
+        assert_eq!(centroids.len(), 2);
+        assert_eq!((centroids[0].group_key.as_str(), centroids[0].count), ("b", 2));
+        assert_eq!(centroids[0].vector, vec![2.0, 1.0]);
+        assert_eq!((centroids[1].group_key.as_str(), centroids[1].count), ("a", 1));
+        assert_eq!(centroids[1].vector, vec![5.0, 5.0]);
+    }
+
+    #[tokio::test]
+    async fn batch_embed_returns_centroids_only_for_group_keys() {
+        let service = service(test_model());
+        let texts: Vec<String> = ["alpha", "beta", "gamma"].iter().map(|t| t.to_string()).collect();
+        let grouped = service
+            .batch_embed(Request::new(BatchEmbedRequest {
+                texts: texts.clone(),
+                group_keys: vec!["x".to_string(), "y".to_string(), "x".to_string()],
+                ..BatchEmbedRequest::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(grouped.centroids.len(), 2);
+        assert_eq!(grouped.centroids[0].count, 2);
+        let x = mean_vector(&[&grouped.embeddings[0].vector[..], &grouped.embeddings[2].vector[..]]);
+        assert_close(&grouped.centroids[0].vector, &x);
+        assert_close(&grouped.centroids[1].vector, &grouped.embeddings[1].vector);
+
+        let plain = service
+            .batch_embed(Request::new(BatchEmbedRequest {
+                texts: texts.clone(),
+                ..BatchEmbedRequest::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(plain.centroids.is_empty());
+
+        let mismatched = service
+            .batch_embed(Request::new(BatchEmbedRequest {
+                texts,
+                group_keys: vec!["x".to_string()],
+                ..BatchEmbedRequest::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(mismatched.code(), tonic::Code::InvalidArgument);
+    }
+
+    fn unavailable(_ordinal: usize) -> candle_core::Result<Device> {
+        Err(candle_core::Error::Msg("not available".to_string()))
+    }
+
+    #[test]
+    fn metal_falls_back_to_cpu_when_unavailable() {
+        assert!(matches!(open_device("metal", unavailable, unavailable).unwrap(), Device::Cpu));
+        assert!(matches!(open_device(" Metal ", unavailable, unavailable).unwrap(), Device::Cpu));
+    }
+
+    #[test]
+    fn unavailable_cuda_fails_instead_of_falling_back() {
+        let error = open_device("cuda:1", unavailable, unavailable).unwrap_err().to_string();
+        assert!(error.contains("CUDA device 1 unavailable (not available)"), "{}", error);
+        let mut ordinal = None;
+        let opened = open_device("cuda", unavailable, |index| {
+            ordinal = Some(index);
+            Ok(Device::Cpu)
+        });
+        assert!(opened.is_ok());
+        assert_eq!(ordinal, Some(0));
+    }
+
+    #[test]
+    fn device_names_are_validated() {
+        assert!(matches!(select_device("").unwrap(), Device::Cpu));
+        assert!(matches!(select_device("CPU").unwrap(), Device::Cpu));
+        let error = select_device("tpu").unwrap_err().to_string();
+        assert!(error.contains("Unsupported device 'tpu'"), "{}", error);
+        let error = select_device("cuda:x").unwrap_err().to_string();
+        assert!(error.contains("expected cuda:<index>"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn model_info_reports_the_device_in_use() {
+        let info = service(test_model())
+            .model_info(Request::new(ModelInfoRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(info.device, "cpu");
+        assert_eq!(info.embedding_dim, HIDDEN as i32);
+    }
+
+    #[tokio::test]
+    async fn return_raw_carries_the_unnormalized_vector() {
+        let service = service(test_model());
+        let response = service
+            .embed(Request::new(EmbedRequest {
+                normalize: Some(true),
+                return_raw: true,
+                ..embed_request("alpha beta")
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let raw = mean_of(&[1, 4, 5, 2]);
+        assert_close(&response.unnormalized_vector, &raw);
+        let mut normalized = raw;
+        l2_normalize(&mut normalized);
+        assert_close(&response.vector, &normalized);
+
+        let unnormalized = service
+            .embed(Request::new(EmbedRequest {
+                normalize: Some(false),
+                return_raw: true,
+                ..embed_request("alpha beta")
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(unnormalized.code(), tonic::Code::InvalidArgument);
+
+        let without_raw = service
+            .embed(Request::new(EmbedRequest {
+                normalize: Some(true),
+                ..embed_request("alpha beta")
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(without_raw.unnormalized_vector.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drift_check_against_own_reference_is_one() {
+        let mut model = test_model();
+        model.drift_canary = "alpha gamma".to_string();
+        model.drift_reference = model.embed("alpha gamma").unwrap();
+        let service = service(model);
+
+        let own = service
+            .drift_check(Request::new(DriftCheckRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!((own.similarity - 1.0).abs() < 1e-5, "{}", own.similarity);
+        assert!(!own.drifted);
+        assert_eq!(own.threshold, DEFAULT_DRIFT_THRESHOLD);
+
+        let other = service
+            .drift_check(Request::new(DriftCheckRequest {
+                reference: vec![0.0, 0.0, 1.0, 0.0],
+                ..DriftCheckRequest::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(other.drifted, "{}", other.similarity);
+
+        let wrong_width = service
+            .drift_check(Request::new(DriftCheckRequest {
+                reference: vec![1.0; HIDDEN + 1],
+                ..DriftCheckRequest::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(wrong_width.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn best_window_evaluates_start_middle_and_end_windows() {
+        let model = test_model();
+        // 10 content tokens against a 6-token content width: windows start at 0, 2 and 4.
+        let text = "alpha alpha alpha alpha alpha alpha delta delta delta delta";
+        let query = model.embed("delta delta delta delta delta delta").unwrap();
+
+        let (vector, windows, tokens) = model.embed_best_window(text, &query).unwrap();
+        assert_eq!(windows, 3);
+        assert_eq!(tokens, CONTEXT);
+        assert_close(&vector, &model.embed("alpha alpha delta delta delta delta").unwrap());
+
+        let (_, windows, _) = model.embed_best_window("alpha beta", &[]).unwrap();
+        assert_eq!(windows, 1);
+    }
+
+    #[test]
+    fn effective_timeout_is_the_tighter_limit() {
+        let (short, long) = (Duration::from_millis(10), Duration::from_millis(20));
+        assert_eq!(effective_timeout(Some(long), Some(short)), Some(short));
+        assert_eq!(effective_timeout(Some(short), Some(long)), Some(short));
+        assert_eq!(effective_timeout(Some(long), None), Some(long));
+        assert_eq!(effective_timeout(None, Some(short)), Some(short));
+        assert_eq!(effective_timeout(None, None), None);
+    }
+
+    #[test]
+    fn client_deadline_parses_grpc_timeout() {
+        let mut request = Request::new(());
+        assert_eq!(client_deadline(&request), None);
+        for (header, expected) in [
+            ("3S", Duration::from_secs(3)),
+            ("250m", Duration::from_millis(250)),
+            ("2M", Duration::from_secs(120)),
+            ("7u", Duration::from_micros(7)),
+        ] {
+            request.metadata_mut().insert("grpc-timeout", header.parse().unwrap());
+            assert_eq!(client_deadline(&request), Some(expected), "{}", header);
+        }
+        request.metadata_mut().insert("grpc-timeout", "5x".parse().unwrap());
+        assert_eq!(client_deadline(&request), None);
+    }
+
+    #[tokio::test]
+    async fn server_default_timeout_fires_without_a_client_deadline() {
+        let service = LLMServiceImpl {
+            timeouts: RpcTimeouts {
+                embed: Some(Duration::from_millis(20)),
+                ..RpcTimeouts::default()
+            },
+            ..service(test_model_with(Duration::from_millis(200)).0)
+        };
+        let error = service.embed(Request::new(embed_request("alpha"))).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::DeadlineExceeded);
+    }
+
+    #[test]
+    fn char_span_slices_on_character_boundaries() {
+        let text = "héllo wörld 日本";
+        // Byte 2 falls inside 'é'; character 2 is the first 'l'.
+        assert!(!text.is_char_boundary(2));
+        assert_eq!(char_span(text, Some(1), Some(5)).unwrap(), "éllo");
+        assert_eq!(char_span(text, Some(12), None).unwrap(), "日本");
+        assert_eq!(char_span(text, None, Some(3)).unwrap(), "hél");
+        assert_eq!(char_span(text, None, None).unwrap(), text);
+        assert_eq!(char_span(text, Some(14), Some(14)).unwrap(), "");
+
+        for (start, end) in [(Some(5), Some(4)), (None, Some(15)), (Some(15), None)] {
+            let error = char_span(text, start, end).unwrap_err();
+            assert_eq!(error.code(), tonic::Code::InvalidArgument, "{:?}..{:?}", start, end);
+        }
+    }
+
+    #[tokio::test]
+    async fn embed_honours_the_character_span() {
+        let service = service(test_model());
+        let span = service
+            .embed(Request::new(EmbedRequest {
+                char_start: Some(6),
+                char_end: Some(10),
+                ..embed_request("alpha beta gamma")
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_close(&span.vector, &mean_of(&[1, 5, 2]));
+    }
+
+    #[test]
+    fn local_directory_with_a_slash_is_not_a_hub_id() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let model = EmbeddingModel::loaded(&init_request(&dir)).unwrap();
+        assert_eq!(model.model_path, dir.path());
+        assert_eq!(model.embedding_dim, 8);
+        assert_eq!(model.embed("alpha beta").unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn classify_returns_logits_and_id2label_names() {
+        let dir = checkpoint(serde_json::json!({ "id2label": { "1": "positive", "0": "negative" } }), true);
+        let service = service(EmbeddingModel::loaded(&init_request(&dir)).unwrap());
+        let response = service
+            .classify(Request::new(ClassifyRequest {
+                text: "alpha beta".to_string(),
+                softmax: true,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.labels, ["negative", "positive"]);
+        assert_eq!(response.logits.len(), 2);
+        assert!((response.probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        assert_close(&response.probabilities, &softmax(&response.logits));
+    }
+
+    #[tokio::test]
+    async fn classify_without_a_head_fails_precondition() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let service = service(EmbeddingModel::loaded(&init_request(&dir)).unwrap());
+        let error = service
+            .classify(Request::new(ClassifyRequest {
+                text: "alpha".to_string(),
+                softmax: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+
+    fn weighted_request(texts: &[&str], weights: &[f32]) -> Request<WeightedEmbedRequest> {
+        Request::new(WeightedEmbedRequest {
+            texts: texts.iter().map(|text| text.to_string()).collect(),
+            weights: weights.to_vec(),
+            normalize: false,
+        })
+    }
+
+    #[test]
+    fn weighted_mean_divides_by_the_weight_sum() {
+        let vectors = [vec![0.0, 4.0], vec![4.0, 0.0]];
+        assert_eq!(weighted_mean(&vectors, &[2.0, 2.0]), [2.0, 2.0]);
+        assert_eq!(weighted_mean(&vectors, &[3.0, 1.0]), [1.0, 3.0]);
+    }
+
+    #[tokio::test]
+    async fn weighted_embed_with_equal_weights_is_the_centroid() {
+        let (alpha, beta) = (mean_of(&[1, 4, 2]), mean_of(&[1, 5, 2]));
+        let response = service(test_model())
+            .weighted_embed(weighted_request(&["alpha", "beta"], &[0.5, 0.5]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_close(&response.vector, &mean_vector(&[&alpha, &beta]));
+    }
+
+    #[tokio::test]
+    async fn weighted_embed_shifts_towards_the_heavier_text() {
+        let (alpha, beta) = (mean_of(&[1, 4, 2]), mean_of(&[1, 5, 2]));
+        let response = service(test_model())
+            .weighted_embed(weighted_request(&["alpha", "beta"], &[3.0, 1.0]))
+            .await
+            .unwrap()
+            .into_inner();
+        let expected: Vec<f32> = alpha.iter().zip(&beta).map(|(a, b)| 0.75 * a + 0.25 * b).collect();
+        assert_close(&response.vector, &expected);
+        let distance = |v: &[f32]| v.iter().zip(&response.vector).map(|(a, b)| (a - b).powi(2)).sum::<f32>();
+        assert!(distance(&alpha) < distance(&beta));
+    }
+
+    #[tokio::test]
+    async fn weighted_embed_rejects_mismatched_or_zero_weights() {
+        let service = service(test_model());
+        for (weights, what) in [(&[1.0][..], "length"), (&[1.0, -1.0][..], "zero sum")] {
+            let error = service.weighted_embed(weighted_request(&["alpha", "beta"], weights)).await.unwrap_err();
+            assert_eq!(error.code(), tonic::Code::InvalidArgument, "{}", what);
+        }
+    }
+
+    #[test]
+    fn identity_projection_leaves_vectors_unchanged() {
+        let dir = TempDir::new();
+        let path = dir.0.join("identity.npy");
+        Tensor::eye(HIDDEN, DType::F32, &Device::Cpu).unwrap().write_npy(&path).unwrap();
+        let mut model = test_model();
+        model.projection = Some(load_projection(path.to_str().unwrap(), HIDDEN, &Device::Cpu).unwrap());
+        assert_close(&model.embed("alpha beta").unwrap(), &mean_of(&[1, 4, 5, 2]));
+    }
+
+    #[test]
+    fn projection_of_the_wrong_height_is_rejected() {
+        let dir = TempDir::new();
+        let path = dir.0.join("projection.npy");
+        Tensor::zeros((HIDDEN + 1, 2), DType::F32, &Device::Cpu).unwrap().write_npy(&path).unwrap();
+        let error = load_projection(path.to_str().unwrap(), HIDDEN, &Device::Cpu).unwrap_err();
+        assert!(error.to_string().contains("expected [4, target_dim]"), "{}", error);
+    }
+
+    #[test]
+    fn init_with_an_identity_projection_keeps_the_vectors() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let path = dir.0.join("projection.npy");
+        Tensor::eye(8, DType::F32, &Device::Cpu).unwrap().write_npy(&path).unwrap();
+        let plain = EmbeddingModel::loaded(&init_request(&dir)).unwrap();
+        let projected = EmbeddingModel::loaded(&InitRequest {
+            projection_path: path.to_str().unwrap().to_string(),
+            ..init_request(&dir)
+        })
+        .unwrap();
+        assert_eq!(projected.embedding_dim, 8);
+        assert_close(&projected.embed("alpha beta").unwrap(), &plain.embed("alpha beta").unwrap());
+    }
+
+    #[tokio::test]
+    async fn identical_init_skips_the_reload_unless_forced() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let service = service(EmbeddingModel::new());
+        let first = service.init_model(Request::new(init_request(&dir))).await.unwrap().into_inner();
+        assert!(first.success, "{}", first.message);
+
+        // A reload would replace the whole model, marker included.
+        service.model.write().await.fingerprint = "marker".to_string();
+        let second = service.init_model(Request::new(init_request(&dir))).await.unwrap().into_inner();
+        assert!(second.success && second.message.contains("reload skipped"), "{}", second.message);
+        assert_eq!(service.model.read().await.fingerprint, "marker");
+
+        let forced = InitRequest {
+            force_reload: true,
+            ..init_request(&dir)
+        };
+        let third = service.init_model(Request::new(forced)).await.unwrap().into_inner();
+        assert!(third.success && !third.message.contains("reload skipped"), "{}", third.message);
+        assert_ne!(service.model.read().await.fingerprint, "marker");
+    }
+
+    #[tokio::test]
+    async fn init_with_different_settings_reloads() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let service = service(EmbeddingModel::new());
+        service.init_model(Request::new(init_request(&dir))).await.unwrap();
+        let changed = InitRequest {
+            pooling: "cls".to_string(),
+            ..init_request(&dir)
+        };
+        let response = service.init_model(Request::new(changed)).await.unwrap().into_inner();
+        assert!(response.success && !response.message.contains("reload skipped"), "{}", response.message);
+    }
+
+    #[test]
+    fn dedup_corpus_keeps_the_first_of_each_near_duplicate() {
+        let entry = |id: &str, vector: Vec<f32>| CorpusEntry {
+            id: id.to_string(),
+            text: String::new(),
+            vector,
+        };
+        let entries = vec![
+            entry("a", vec![1.0, 0.0]),
+            entry("b", vec![0.0, 1.0]),
+            entry("a'", vec![1.0, 0.01]),
+            entry("c", vec![1.0, 1.0]),
+        ];
+        let kept: Vec<String> = dedup_corpus(entries, 0.99).into_iter().map(|entry| entry.id).collect();
+        assert_eq!(kept, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn load_corpus_reports_near_duplicates_dropped() {
+        // "alpha alpha" pools to a vector within 0.998 cosine of "alpha"; the
+        // other pairs are further apart.
+        let service = service(test_model());
+        let texts = ["alpha", "beta", "alpha alpha", "gamma"];
+        let response = service
+            .load_corpus(Request::new(LoadCorpusRequest {
+                texts: texts.iter().map(|text| text.to_string()).collect(),
+                ids: Vec::new(),
+                dedup_threshold: 0.998,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((response.loaded, response.deduplicated), (3, 1));
+        let ids: Vec<String> = service.model.read().await.corpus.iter().map(|entry| entry.id.clone()).collect();
+        assert_eq!(ids, ["0", "1", "3"]);
+    }
+
+    #[tokio::test]
+    async fn load_corpus_keeps_everything_at_threshold_zero() {
+        let service = service(test_model());
+        let response = service
+            .load_corpus(Request::new(LoadCorpusRequest {
+                texts: vec!["alpha".to_string(), "alpha".to_string()],
+                ids: Vec::new(),
+                dedup_threshold: 0.0,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((response.loaded, response.deduplicated), (2, 0));
+    }
+
+    #[test]
+    fn invalid_text_policy_handles_replacement_characters() {
+        let mangled = "alpha\u{FFFD} beta";
+
+        let mut text = mangled.to_string();
+        assert!(!InvalidTextPolicy::Allow.apply(&mut text).unwrap());
+        assert_eq!(text, mangled);
+
+        let mut text = mangled.to_string();
+        let error = InvalidTextPolicy::Reject.apply(&mut text).unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+        assert!(error.message().contains("at char 5"), "{}", error.message());
+
+        let mut text = mangled.to_string();
+        assert!(InvalidTextPolicy::Sanitize.apply(&mut text).unwrap());
+        assert_eq!(text, "alpha beta");
+
+        let mut clean = "alpha beta".to_string();
+        assert!(!InvalidTextPolicy::Sanitize.apply(&mut clean).unwrap());
+    }
+
+    #[tokio::test]
+    async fn embed_applies_the_invalid_text_policy() {
+        let with_policy = |policy| {
+            let mut model = test_model();
+            model.invalid_text_policy = policy;
+            service(model)
+        };
+        let mangled = || Request::new(embed_request("alpha\u{FFFD} beta"));
+
+        // Allowed through, the damaged word is an unknown token.
+        let allowed = with_policy(InvalidTextPolicy::Allow).embed(mangled()).await.unwrap().into_inner();
+        assert!(!allowed.sanitized);
+        assert_close(&allowed.vector, &mean_of(&[1, 3, 5, 2]));
+
+        let error = with_policy(InvalidTextPolicy::Reject).embed(mangled()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+
+        let sanitized = with_policy(InvalidTextPolicy::Sanitize).embed(mangled()).await.unwrap().into_inner();
+        assert!(sanitized.sanitized);
+        assert_close(&sanitized.vector, &mean_of(&[1, 4, 5, 2]));
+    }
+
+    #[tokio::test]
+    async fn text_of_only_replacement_characters_is_blank_once_sanitized() {
+        let mut model = test_model();
+        model.invalid_text_policy = InvalidTextPolicy::Sanitize;
+        let error = service(model).embed(Request::new(embed_request("\u{FFFD} \u{FFFD}"))).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    async fn service_with_labels() -> LLMServiceImpl {
+        let service = service(test_model());
+        let response = service
+            .load_labels(Request::new(LoadLabelsRequest {
+                names: vec!["first".to_string(), "second".to_string(), "third".to_string()],
+                descriptions: vec!["alpha".to_string(), "beta".to_string(), "gamma".to_string()],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.loaded, 3);
+        service
+    }
+
+    fn zero_shot_request(text: &str, top_k: i32) -> Request<ZeroShotClassifyRequest> {
+        Request::new(ZeroShotClassifyRequest {
+            text: text.to_string(),
+            top_k,
+            softmax: true,
+            temperature: 0.0,
+        })
+    }
+
+    #[tokio::test]
+    async fn zero_shot_classify_picks_the_matching_label() {
+        let service = service_with_labels().await;
+        for (text, expected) in [("beta", "second"), ("alpha", "first"), ("gamma", "third")] {
+            let response = service.zero_shot_classify(zero_shot_request(text, 0)).await.unwrap().into_inner();
+            assert_eq!(response.labels.len(), 1);
+            assert_eq!(response.labels[0].name, expected, "for {:?}", text);
+            assert!((response.labels[0].score - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_shot_classify_ranks_top_k_with_a_softmax_over_all_labels() {
+        let service = service_with_labels().await;
+        let response = service.zero_shot_classify(zero_shot_request("beta", 3)).await.unwrap().into_inner();
+        assert_eq!(response.labels.len(), 3);
+        assert!(response.labels.windows(2).all(|pair| pair[0].score >= pair[1].score));
+        assert!(response.labels.windows(2).all(|pair| pair[0].probability >= pair[1].probability));
+        let total: f32 = response.labels.iter().map(|label| label.probability).sum();
+        assert!((total - 1.0).abs() < 1e-5, "{}", total);
+    }
+
+    #[tokio::test]
+    async fn zero_shot_classify_without_labels_fails_precondition() {
+        let error = service(test_model()).zero_shot_classify(zero_shot_request("alpha", 1)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn explain_scores_the_query_token_above_filler() {
+        let response = service(test_model())
+            .explain(Request::new(ExplainRequest {
+                query: "beta".to_string(),
+                document: "gamma beta gamma".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let tokens: Vec<&str> = response.tokens.iter().map(|token| token.token.as_str()).collect();
+        assert_eq!(tokens, ["gamma", "beta", "gamma"]);
+        let importance: Vec<f32> = response.tokens.iter().map(|token| token.importance).collect();
+        // Dropping "beta" moves the document away from the query; dropping
+        // filler moves it closer.
+        assert!(importance[1] > 0.0, "{:?}", importance);
+        assert!(importance[0] < 0.0 && importance[2] < 0.0, "{:?}", importance);
+        assert_eq!((response.tokens[1].char_start, response.tokens[1].char_end), (6, 10));
+    }
+
+    #[tokio::test]
+    async fn mismatched_expected_dim_fails_precondition() {
+        let service = service(test_model());
+        let request = |expected_dim| {
+            Request::new(EmbedRequest {
+                expected_dim,
+                ..embed_request("alpha")
+            })
+        };
+        let error = service.embed(request(HIDDEN as i32 + 1)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+        assert!(error.metadata().get(DIM_MISMATCH_KEY).is_some());
+        assert_eq!(service.embed(request(HIDDEN as i32)).await.unwrap().into_inner().dim, HIDDEN as i32);
+        assert_eq!(service.embed(request(0)).await.unwrap().into_inner().vector.len(), HIDDEN);
+    }
+
+    #[test]
+    fn random_projection_is_determined_by_its_seed() {
+        let matrix = |seed| {
+            random_projection(8, 4, seed, &Device::Cpu).unwrap().flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        };
+        assert_eq!(matrix(7), matrix(7));
+        assert_ne!(matrix(7), matrix(8));
+    }
+
+    #[test]
+    fn same_seed_projects_identically_across_instances() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let projected = |seed| {
+            EmbeddingModel::loaded(&InitRequest {
+                random_projection_dim: 4,
+                random_projection_seed: seed,
+                ..init_request(&dir)
+            })
+            .unwrap()
+        };
+        let (first, second, other) = (projected(7), projected(7), projected(8));
+        assert_eq!(first.embedding_dim, 4);
+        let vector = first.embed("alpha beta").unwrap();
+        assert_eq!(vector.len(), 4);
+        assert_eq!(vector, second.embed("alpha beta").unwrap());
+        assert_ne!(vector, other.embed("alpha beta").unwrap());
+    }
+
+    #[test]
+    fn random_projection_dim_above_the_hidden_size_is_rejected() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let error = EmbeddingModel::loaded(&InitRequest {
+            random_projection_dim: 9,
+            ..init_request(&dir)
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("within 1..=8"), "{}", error);
+    }
+
+    #[test]
+    fn without_lowercase_drops_only_the_lowercasing() {
+        use tokenizers::normalizers::{BertNormalizer, Lowercase, NFC};
+
+        assert!(without_lowercase(&NormalizerWrapper::Lowercase(Lowercase)).is_none());
+
+        let bert = BertNormalizer::new(true, true, None, true);
+        match without_lowercase(&NormalizerWrapper::BertNormalizer(bert)) {
+            Some(NormalizerWrapper::BertNormalizer(cased)) => {
+                assert!(!cased.lowercase);
+                assert_eq!(cased.strip_accents, Some(true));
+            }
+            other => panic!("expected a BertNormalizer, got {:?}", other),
+        }
+
+        let sequence = Sequence::new(vec![NormalizerWrapper::NFC(NFC), NormalizerWrapper::Lowercase(Lowercase)]);
+        match without_lowercase(&NormalizerWrapper::Sequence(sequence)) {
+            Some(NormalizerWrapper::Sequence(sequence)) => {
+                assert!(matches!(sequence.get_normalizers(), [NormalizerWrapper::NFC(_)]));
+            }
+            other => panic!("expected a one-step Sequence, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disable_lowercase_keeps_mixed_case_tokens_distinct() {
+        use tokenizers::normalizers::Lowercase;
+
+        let dir = checkpoint(serde_json::json!({}), false);
+        let mut lowercasing = test_tokenizer();
+        lowercasing.with_normalizer(Some(NormalizerWrapper::Lowercase(Lowercase)));
+        lowercasing.save(dir.0.join("tokenizer.json"), false).unwrap();
+
+        let ids = |disable_lowercase| {
+            let model = EmbeddingModel::loaded(&InitRequest {
+                disable_lowercase,
+                ..init_request(&dir)
+            })
+            .unwrap();
+            let encoding = model.tokenizer().unwrap().encode("Alpha alpha", true).unwrap();
+            encoding.get_ids().to_vec()
+        };
+        assert_eq!(ids(false), [1, 4, 4, 2]);
+        // "Alpha" is out of the lowercase vocabulary once case is kept.
+        assert_eq!(ids(true), [1, 3, 4, 2]);
+    }
+
+    #[test]
+    fn long_context_config_is_not_truncated_at_512() {
+        let dir = checkpoint(serde_json::json!({ "max_position_embeddings": 1024 }), false);
+        let model = EmbeddingModel::loaded(&init_request(&dir)).unwrap();
+        assert_eq!(model.max_position_embeddings, 1024);
+
+        let words = |n: usize| vec!["alpha"; n].join(" ");
+        let (_, truncated, tokens) = model.embed_with_policy(&words(600)).unwrap();
+        assert_eq!((truncated, tokens), (false, 602));
+        // The config's own limit still applies.
+        let (_, truncated, tokens) = model.embed_with_policy(&words(1100)).unwrap();
+        assert_eq!((truncated, tokens), (true, 1024));
+    }
+
+    #[tokio::test]
+    async fn debug_embed_carries_the_settings_that_produced_it() {
+        let mut model = test_model();
+        model.model_path = "test-model".to_string();
+        model.exclude_special_tokens = true;
+        let service = service(model);
+
+        let plain = service.embed(Request::new(embed_request("alpha"))).await.unwrap().into_inner();
+        assert!(plain.provenance.is_none());
+
+        let request = EmbedRequest {
+            debug: true,
+            normalize: Some(true),
+            ..embed_request("alpha")
+        };
+        let provenance = service.embed(Request::new(request)).await.unwrap().into_inner().provenance.unwrap();
+        assert_eq!(provenance.sidecar_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(provenance.candle_version, env!("SIDECAR_CANDLE_VERSION"));
+        assert_eq!(provenance.tokenizers_version, env!("SIDECAR_TOKENIZERS_VERSION"));
+        assert!(!provenance.candle_version.is_empty() && !provenance.tokenizers_version.is_empty());
+        assert_eq!(provenance.model_path, "test-model");
+        assert_eq!(provenance.model_fingerprint, "test-fingerprint");
+        assert_eq!(provenance.device, "cpu");
+        assert_eq!(provenance.pooling, "mean");
+        assert!(provenance.exclude_special_tokens);
+        assert!(provenance.normalized);
+    }
+
+    fn pooled_with(pooling: Pooling, texts: &[&str]) -> Vec<Vec<f32>> {
+        let mut model = test_model();
+        model.pooling = pooling;
+        let texts: Vec<String> = texts.iter().map(|text| text.to_string()).collect();
+        model.embed_batch(&texts).unwrap()
+    }
+
+    #[test]
+    fn cls_pooling_returns_the_first_token_state() {
+        let mut model = test_model();
+        model.pooling = Pooling::Cls;
+        assert_close(&model.embed("alpha beta").unwrap(), &row(1));
+        assert_close(&pooled_with(Pooling::Cls, &["gamma", "alpha beta delta"])[1], &row(1));
+    }
+
+    #[test]
+    fn max_pooling_ignores_padding() {
+        // "alpha" is padded to the length of the other entry; the [PAD] row's
+        // last component (0) would beat every real one (all negative).
+        let vectors = pooled_with(Pooling::Max, &["alpha", "alpha beta delta"]);
+        assert_close(&vectors[0], &[4.0, 1.0, 1.0, -1.0]);
+        assert_close(&vectors[1], &[7.0, 1.0, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn mean_pooling_is_the_default() {
+        assert_eq!(Pooling::parse("").unwrap(), Pooling::Mean);
+        assert_close(&pooled_with(Pooling::Mean, &["alpha", "alpha beta"])[0], &mean_of(&[1, 4, 2]));
+        assert!(Pooling::parse("median").is_err());
+    }
+
+    /// The error hf-hub returns for an HTTP error status.
+    fn http_error(response: &str) -> ApiError {
+        let response: ureq::Response = response.parse().unwrap();
+        ApiError::RequestError(Box::new(ureq::Error::Status(response.status(), response)))
+    }
+
+    #[test]
+    fn rate_limit_delay_follows_retry_after_within_bounds() {
+        let delay = |headers: &str| {
+            rate_limit_delay(&http_error(&format!("HTTP/1.1 429 Too Many Requests\r\n{}\r\n", headers)))
+        };
+        assert_eq!(delay("Retry-After: 2\r\n"), Some(Duration::from_secs(2)));
+        assert_eq!(delay(""), Some(DEFAULT_RATE_LIMIT_WAIT));
+        assert_eq!(delay("Retry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n"), Some(DEFAULT_RATE_LIMIT_WAIT));
+        assert_eq!(delay("Retry-After: 86400\r\n"), Some(MAX_RATE_LIMIT_WAIT));
+        assert_eq!(rate_limit_delay(&http_error("HTTP/1.1 500 Internal Server Error\r\n\r\n")), None);
+    }
+
+    #[test]
+    fn rate_limited_fetch_waits_retry_after_then_succeeds() {
+        let mut attempts = Vec::new();
+        let started = std::time::Instant::now();
+        let path = fetch_with_retries("config.json", std::path::Path::new("/nonexistent"), 3, || {
+            attempts.push(started.elapsed());
+            if attempts.len() == 1 {
+                Err(http_error("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\n\r\n"))
+            } else {
+                Ok("config.json".into())
+            }
+        })
+        .unwrap();
+        assert_eq!(path, std::path::PathBuf::from("config.json"));
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[1] - attempts[0] >= Duration::from_secs(1), "{:?}", attempts);
+    }
+
+    #[test]
+    fn rate_limiting_past_the_retries_is_reported_as_transient() {
+        let mut attempts = 0;
+        let error = fetch_with_retries("config.json", std::path::Path::new("/nonexistent"), 0, || {
+            attempts += 1;
+            Err(http_error("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\n\r\n"))
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+        assert!(error.to_string().contains("Rate limited by HuggingFace (HTTP 429)"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn each_truncation_policy_handles_over_length_input() {
+        let with_policy = |policy| {
+            let mut model = test_model();
+            model.truncation_policy = policy;
+            service(model)
+        };
+        // Ten words and [CLS]/[SEP] against a context of eight.
+        let long = || Request::new(embed_request(&vec!["alpha"; 10].join(" ")));
+        let cut = mean_of(&[1, 4, 4, 4, 4, 4, 4, 2]);
+
+        let error = with_policy(TruncationPolicy::Error).embed(long()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+        assert!(error.message().contains("12 tokens, over the model limit of 8"), "{}", error.message());
+
+        let flagged = with_policy(TruncationPolicy::Flag).embed(long()).await.unwrap().into_inner();
+        assert!(flagged.was_truncated);
+        assert_close(&flagged.vector, &cut);
+
+        let silent = with_policy(TruncationPolicy::Silent).embed(long()).await.unwrap().into_inner();
+        assert!(!silent.was_truncated);
+        assert_close(&silent.vector, &cut);
+    }
+
+    #[tokio::test]
+    async fn input_within_the_context_is_never_truncated() {
+        for policy in [TruncationPolicy::Error, TruncationPolicy::Flag, TruncationPolicy::Silent] {
+            let mut model = test_model();
+            model.truncation_policy = policy;
+            let response = service(model).embed(Request::new(embed_request("alpha beta"))).await.unwrap();
+            assert!(!response.into_inner().was_truncated, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn truncation_policy_defaults_to_flag() {
+        assert_eq!(TruncationPolicy::parse("").unwrap(), TruncationPolicy::Flag);
+        assert_eq!(TruncationPolicy::parse("ERROR").unwrap(), TruncationPolicy::Error);
+        assert!(TruncationPolicy::parse("drop").is_err());
+    }
+
+    /// Encoder that drops the last output position, like an architecture
+    /// whose output sequence doesn't line up with its input tokens.
+    struct ShorteningEncoder(Box<dyn encoder::Embed>);
+
+    impl encoder::Embed for ShorteningEncoder {
+        fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+            let hidden = self.0.forward(input_ids, attention_mask)?;
+            let seq = hidden.dim(1)?;
+            hidden.narrow(1, 0, seq - 1)
+        }
+    }
+
+    fn shortening_model(sequence_mismatch: SequenceMismatch) -> EmbeddingModel {
+        let mut model = test_model();
+        model.model = Some(Box::new(ShorteningEncoder(model.model.take().unwrap())));
+        model.sequence_mismatch = sequence_mismatch;
+        model
+    }
+
+    #[test]
+    fn mismatched_sequence_length_fails_by_default() {
+        let model = shortening_model(SequenceMismatch::Error);
+        let error = model.embed("alpha beta").unwrap_err();
+        assert!(error.to_string().contains("returned 3 positions for 4 input tokens"), "{}", error);
+        assert!(model.embed_batch(&["alpha beta".to_string()]).is_err());
+    }
+
+    #[test]
+    fn pool_all_pools_every_output_position_unmasked() {
+        let model = shortening_model(SequenceMismatch::PoolAll);
+        // [CLS] alpha beta [SEP] comes back as [CLS] alpha beta.
+        assert_close(&model.embed("alpha beta").unwrap(), &mean_of(&[1, 4, 5]));
+        let vectors = model.embed_batch(&["alpha beta".to_string(), "gamma".to_string()]).unwrap();
+        assert_close(&vectors[0], &mean_of(&[1, 4, 5]));
+        // "gamma" is padded to four positions, and nothing is masked any more.
+        assert_close(&vectors[1], &mean_of(&[1, 6, 2]));
+    }
+
+    #[test]
+    fn clip_components_clamps_only_beyond_the_limit() {
+        let mut vector = vec![3.0, -0.5, 1.0, -4.0, 2.0];
+        clip_components(&mut vector, 2.0);
+        assert_eq!(vector, [2.0, -0.5, 1.0, -2.0, 2.0]);
+    }
+
+    #[test]
+    fn abs_percentile_uses_the_nearest_rank() {
+        let vector = [-4.0, 1.0, 3.0, -2.0];
+        assert_eq!(abs_percentile(&vector, 50.0), 2.0);
+        assert_eq!(abs_percentile(&vector, 75.0), 3.0);
+        assert_eq!(abs_percentile(&vector, 99.0), 4.0);
+    }
+
+    #[tokio::test]
+    async fn embed_clips_after_pooling() {
+        // "alpha beta" pools to [3, 1, 0.5, -3].
+        let service = service(test_model());
+        let clipped = |clip_value, clip_percentile| {
+            Request::new(EmbedRequest {
+                clip_value,
+                clip_percentile,
+                ..embed_request("alpha beta")
+            })
+        };
+        let by_value = service.embed(clipped(2.0, 0.0)).await.unwrap().into_inner();
+        assert_close(&by_value.vector, &[2.0, 1.0, 0.5, -2.0]);
+        let by_percentile = service.embed(clipped(0.0, 50.0)).await.unwrap().into_inner();
+        assert_close(&by_percentile.vector, &[1.0, 1.0, 0.5, -1.0]);
+        let error = service.embed(clipped(2.0, 50.0)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    fn batch_with_blanks(policy: &str) -> Request<BatchEmbedRequest> {
+        Request::new(BatchEmbedRequest {
+            texts: ["alpha", "", "beta", "  \t"].iter().map(|text| text.to_string()).collect(),
+            empty_input_policy: policy.to_string(),
+            ..BatchEmbedRequest::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn empty_batch_entries_fail_the_batch_by_default() {
+        let service = service(test_model());
+        for policy in ["", "error"] {
+            let error = service.batch_embed(batch_with_blanks(policy)).await.unwrap_err();
+            assert_eq!(error.code(), tonic::Code::InvalidArgument);
+            assert!(error.message().contains("texts[1]"), "{}", error.message());
+        }
+    }
+
+    #[tokio::test]
+    async fn skipped_empty_entries_are_left_out_and_listed() {
+        let response = service(test_model()).batch_embed(batch_with_blanks("skip")).await.unwrap().into_inner();
+        assert_eq!(response.empty_indices, [1, 3]);
+        assert_eq!(response.embeddings.len(), 2);
+        assert_close(&response.embeddings[0].vector, &mean_of(&[1, 4, 2]));
+        assert_close(&response.embeddings[1].vector, &mean_of(&[1, 5, 2]));
+    }
+
+    #[tokio::test]
+    async fn zero_vector_fills_empty_entries_in_place() {
+        let response = service(test_model()).batch_embed(batch_with_blanks("zero_vector")).await.unwrap().into_inner();
+        assert_eq!(response.empty_indices, [1, 3]);
+        let vectors: Vec<&[f32]> = response.embeddings.iter().map(|e| e.vector.as_slice()).collect();
+        assert_eq!(vectors.len(), 4);
+        assert_close(vectors[0], &mean_of(&[1, 4, 2]));
+        assert_eq!(vectors[1], [0.0; HIDDEN]);
+        assert_close(vectors[2], &mean_of(&[1, 5, 2]));
+        assert_eq!(vectors[3], [0.0; HIDDEN]);
+    }
+
+    #[tokio::test]
+    async fn unknown_empty_input_policy_is_rejected() {
+        let error = service(test_model()).batch_embed(batch_with_blanks("drop")).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn both_weights_loading_modes_load_the_same_model() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let embed = |weights_loading: &str| {
+            let req = InitRequest {
+                weights_loading: weights_loading.to_string(),
+                ..init_request(&dir)
+            };
+            EmbeddingModel::loaded(&req).unwrap().embed("alpha beta").unwrap()
+        };
+        assert_eq!(embed("mmap"), embed("buffered"));
+        assert!(WeightsLoading::parse("lazy").is_err());
+    }
+
+    /// Replace `dir`'s weights with a FIFO that a background thread feeds the
+    /// original bytes through once: it reads like the file, but can't be
+    /// memory-mapped.
+    #[cfg(unix)]
+    fn weights_through_fifo(dir: &TempDir) {
+        let path = dir.0.join("model.safetensors");
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+        std::thread::spawn(move || {
+            use std::io::Write;
+            if let Ok(mut fifo) = std::fs::OpenOptions::new().write(true).open(&path) {
+                let _ = fifo.write_all(&bytes);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn buffered_weights_loading_reads_the_file() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        weights_through_fifo(&dir);
+        let req = InitRequest {
+            weights_loading: "buffered".to_string(),
+            ..init_request(&dir)
+        };
+        assert_eq!(EmbeddingModel::loaded(&req).unwrap().embed("alpha").unwrap().len(), 8);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mmap_weights_loading_maps_the_file() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        weights_through_fifo(&dir);
+        assert!(EmbeddingModel::loaded(&init_request(&dir)).is_err());
+    }
+
+    #[tokio::test]
+    async fn timing_reports_every_phase_summing_to_the_total() {
+        // The forward pass sleeps, so it dominates and the phases can't all
+        // round down to zero.
+        let (model, _) = test_model_with(Duration::from_millis(50));
+        let service = service(model);
+
+        let plain = service.embed(Request::new(embed_request("alpha beta"))).await.unwrap().into_inner();
+        assert!(plain.timing.is_none());
+
+        let request = EmbedRequest {
+            timing: true,
+            ..embed_request("alpha beta")
+        };
+        let timing = service.embed(Request::new(request)).await.unwrap().into_inner().timing.unwrap();
+        let phases = [timing.queue_us, timing.tokenize_us, timing.forward_us, timing.pool_us, timing.postprocess_us];
+        assert!(phases.iter().all(|&us| us >= 0), "{:?}", timing);
+        assert!(timing.forward_us >= 50_000, "{:?}", timing);
+        let sum: i64 = phases.iter().sum();
+        // Only the gaps between phases go unaccounted for.
+        assert!(sum <= timing.total_us, "{:?}", timing);
+        assert!(sum as f64 >= 0.9 * timing.total_us as f64, "{:?}", timing);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn embeds_interleaved_with_inits_match_the_model_that_served_them() {
+        let narrow = checkpoint(serde_json::json!({}), false);
+        let wide = checkpoint(serde_json::json!({ "hidden_size": 16, "intermediate_size": 32 }), false);
+        for reload_mode in ["swap", "replace"] {
+            let init = |dir: &TempDir| InitRequest {
+                reload_mode: reload_mode.to_string(),
+                ..init_request(dir)
+            };
+            let dims: std::collections::HashMap<String, usize> = [(&narrow, 8), (&wide, 16)]
+                .into_iter()
+                .map(|(dir, dim)| (EmbeddingModel::loaded(&init(dir)).unwrap().fingerprint, dim))
+                .collect();
+            let service = Arc::new(service(EmbeddingModel::loaded(&init(&narrow)).unwrap()));
+
+            let embeds: Vec<_> = (0..40)
+                .map(|i| {
+                    let service = service.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_millis(i * 5)).await;
+                        service.embed(Request::new(embed_request("alpha beta"))).await.map(Response::into_inner)
+                    })
+                })
+                .collect();
+            for dir in [&wide, &narrow, &wide, &narrow] {
+                let response = service.init_model(Request::new(init(dir))).await.unwrap().into_inner();
+                assert!(response.success, "{}", response.message);
+            }
+
+            for embed in embeds {
+                // Swap never leaves a request without a model; replace makes
+                // them wait for the new one.
+                let response = embed.await.unwrap().unwrap();
+                let dim = dims[&response.model_fingerprint];
+                assert_eq!((response.dim as usize, response.vector.len()), (dim, dim), "{}", reload_mode);
+            }
+        }
+    }
+
+    #[test]
+    fn padded_batch_entry_matches_the_single_embedding() {
+        let (model, _) = test_model_with(Duration::ZERO);
+        let texts = ["alpha".to_string(), "alpha beta gamma delta".to_string()];
+        let batch = model.embed_batch(&texts).unwrap();
+        // "alpha" is padded with three [PAD] positions in the batch.
+        assert_close(&batch[0], &model.embed("alpha").unwrap());
+        assert_close(&batch[1], &model.embed("alpha beta gamma delta").unwrap());
+    }
+
+    /// Encoder whose every forward pass fails, like a lost accelerator.
+    struct FailingEncoder;
+
+    impl encoder::Embed for FailingEncoder {
+        fn forward(&self, _input_ids: &Tensor, _attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+            Err(candle_core::Error::Msg("device lost".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_primary_is_retried_on_the_fallback() {
+        let mut fallback = test_model();
+        fallback.fingerprint = "fallback-fingerprint".to_string();
+        let mut model = test_model();
+        model.model = Some(Box::new(FailingEncoder));
+        model.fallback = Some(Box::new(fallback));
+
+        let response = service(model).embed(Request::new(embed_request("alpha beta"))).await.unwrap().into_inner();
+        assert!(response.used_fallback);
+        assert_eq!(response.model_fingerprint, "fallback-fingerprint");
+        assert_close(&response.vector, &mean_of(&[1, 4, 5, 2]));
+    }
+
+    #[tokio::test]
+    async fn failed_primary_without_a_fallback_is_an_internal_error() {
+        let mut model = test_model();
+        model.model = Some(Box::new(FailingEncoder));
+        let error = service(model).embed(Request::new(embed_request("alpha beta"))).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::Internal);
+        assert!(error.message().contains("device lost"), "{}", error.message());
+    }
+
+    #[test]
+    fn disk_full_download_names_the_cache_and_removes_partial_files() {
+        let cache = TempDir::new();
+        let blobs = cache.0.join("blobs");
+        std::fs::create_dir_all(&blobs).unwrap();
+        std::fs::write(blobs.join("0123abcd.sync.part"), b"half a download").unwrap();
+        std::fs::write(blobs.join("4567ef01"), b"a complete blob").unwrap();
+
+        let mut attempts = 0;
+        let error = fetch_with_retries("model.safetensors", &cache.0, 3, || {
+            attempts += 1;
+            Err(ApiError::IoError(std::io::Error::from_raw_os_error(28)))
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+        let expected = format!(
+            "Disk full while downloading model.safetensors into HuggingFace cache {}",
+            cache.0.display()
+        );
+        assert!(error.to_string().starts_with(&expected), "{}", error);
+        assert!(!blobs.join("0123abcd.sync.part").exists());
+        assert!(blobs.join("4567ef01").exists());
+    }
+
+    /// Every chunk StreamTokenEmbed sends for `text`, in order.
+    async fn token_chunks(service: &LLMServiceImpl, text: &str, rows_per_chunk: i32) -> Vec<TokenEmbedChunk> {
+        let request = StreamTokenEmbedRequest {
+            text: text.to_string(),
+            rows_per_chunk,
+        };
+        let mut stream = service.stream_token_embed(Request::new(request)).await.unwrap().into_inner().into_inner();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.recv().await {
+            chunks.push(chunk.unwrap());
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn streamed_token_chunks_reassemble_the_unary_matrix() {
+        let service = service(test_model());
+        let text = "alpha beta gamma delta";
+        let unary = service
+            .token_embed(Request::new(TokenEmbedRequest { text: text.to_string() }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Six rows at four per chunk.
+        let chunks = token_chunks(&service, text, 4).await;
+        assert_eq!(chunks.iter().map(|chunk| chunk.row_offset).collect::<Vec<_>>(), [0, 4]);
+        assert_eq!(chunks.iter().map(|chunk| chunk.token_vectors.len()).collect::<Vec<_>>(), [4, 2]);
+        assert!(chunks.iter().all(|chunk| chunk.total_rows == 6 && chunk.dim == unary.dim));
+
+        let rows: Vec<Embedding> = chunks.iter().flat_map(|chunk| chunk.token_vectors.clone()).collect();
+        assert_eq!(rows, unary.token_vectors);
+        let tokens: Vec<String> = chunks.iter().flat_map(|chunk| chunk.tokens.clone()).collect();
+        assert_eq!(tokens, unary.tokens);
+        let ids: Vec<u32> = chunks.iter().flat_map(|chunk| chunk.token_ids.clone()).collect();
+        assert_eq!(ids, unary.token_ids);
+    }
+
+    #[tokio::test]
+    async fn stream_token_embed_without_a_model_sends_the_error() {
+        let request = StreamTokenEmbedRequest {
+            text: "alpha".to_string(),
+            rows_per_chunk: 0,
+        };
+        let service = service(EmbeddingModel::new());
+        let mut stream = service.stream_token_embed(Request::new(request)).await.unwrap().into_inner().into_inner();
+        let error = stream.recv().await.unwrap().unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+        assert!(stream.recv().await.is_none());
+    }
+
+    /// Wait up to half a second of real time for `done`; blocking work runs
+    /// on real threads even while tokio's clock is paused.
+    fn wait_for(done: impl Fn() -> bool) -> bool {
+        for _ in 0..500 {
+            if done() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        done()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_embeds_only_once_idle() {
+        let (model, forwards) = test_model_with(Duration::ZERO);
+        let activity = Arc::new(ActivityClock::new());
+        let interval = Duration::from_secs(10);
+        let keepalive = tokio::spawn(run_keepalive(Arc::new(RwLock::new(model)), activity.clone(), interval));
+
+        // Traffic every 5 s keeps the model from ever being idle for 10 s.
+        for _ in 0..6 {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            activity.touch();
+        }
+        assert!(!wait_for(|| forwards.load(Ordering::SeqCst) > 0));
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        assert!(wait_for(|| forwards.load(Ordering::SeqCst) > 0));
+        keepalive.abort();
+    }
+
+    fn joined_request(texts: &[&str]) -> Request<BatchEmbedRequest> {
+        Request::new(BatchEmbedRequest {
+            texts: texts.iter().map(|text| text.to_string()).collect(),
+            join_with: Some(" ".to_string()),
+            ..BatchEmbedRequest::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn join_with_embeds_the_joined_text_not_the_mean_of_the_parts() {
+        let service = service(test_model());
+        let response = service.batch_embed(joined_request(&["alpha", "beta gamma"])).await.unwrap().into_inner();
+        assert_eq!(response.embeddings.len(), 1);
+        let joined = &response.embeddings[0].vector;
+        assert_close(joined, &mean_of(&[1, 4, 5, 6, 2]));
+        assert_close(joined, &test_model().embed("alpha beta gamma").unwrap());
+
+        let separate = mean_vector(&[&mean_of(&[1, 4, 2]), &mean_of(&[1, 5, 6, 2])]);
+        assert!(joined.iter().zip(&separate).any(|(a, b)| (a - b).abs() > 1e-3), "{:?}", separate);
+    }
+
+    #[tokio::test]
+    async fn join_over_the_context_is_invalid_argument() {
+        // Eight words and [CLS]/[SEP] against a context of eight.
+        let texts = ["alpha beta"; 4];
+        let error = service(test_model()).batch_embed(joined_request(&texts)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+        assert!(error.message().contains("Joined input is 10 tokens"), "{}", error.message());
+    }
+
+    #[tokio::test]
+    async fn token_offsets_map_back_to_multibyte_input() {
+        let text = "héllo alpha ünïcödé beta";
+        let response = service(test_model())
+            .tokenize_with_offsets(Request::new(TokenizeRequest { text: text.to_string() }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let tokens: Vec<&str> = response.tokens.iter().map(|span| span.token.as_str()).collect();
+        assert_eq!(tokens, ["[CLS]", "[UNK]", "alpha", "[UNK]", "beta", "[SEP]"]);
+        let has_span: Vec<bool> = response.tokens.iter().map(|span| span.has_span).collect();
+        assert_eq!(has_span, [false, true, true, true, true, false]);
+
+        let chars: Vec<char> = text.chars().collect();
+        let sources: Vec<String> = response
+            .tokens
+            .iter()
+            .filter(|span| span.has_span)
+            .map(|span| chars[span.char_start as usize..span.char_end as usize].iter().collect())
+            .collect();
+        assert_eq!(sources, ["héllo", "alpha", "ünïcödé", "beta"]);
+    }
+
+    #[tokio::test]
+    async fn tokenize_without_a_model_fails_precondition() {
+        let error = service(EmbeddingModel::new())
+            .tokenize_with_offsets(Request::new(TokenizeRequest { text: "alpha".to_string() }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn exclude_special_tokens_pools_content_only() {
+        let mut model = test_model();
+        model.exclude_special_tokens = true;
+        // [CLS] alpha beta [SEP] pools alpha and beta alone: [4.5, 1, 0.5, -4.5].
+        assert_close(&model.embed("alpha beta").unwrap(), &[4.5, 1.0, 0.5, -4.5]);
+        let batch = model.embed_batch(&["alpha beta".to_string(), "gamma".to_string()]).unwrap();
+        assert_close(&batch[0], &[4.5, 1.0, 0.5, -4.5]);
+        assert_close(&batch[1], &row(6));
+    }
+
+    #[test]
+    fn ignored_token_ids_do_not_affect_the_pooled_vector() {
+        let mut model = test_model();
+        model.pooling_ignore_ids = [6].into();
+        let expected = mean_of(&[1, 4, 5, 2]);
+        assert_close(&model.embed("alpha gamma beta").unwrap(), &expected);
+        assert_close(&model.embed("gamma alpha beta gamma").unwrap(), &expected);
+        let batch = model.embed_batch(&["alpha gamma beta".to_string(), "delta".to_string()]).unwrap();
+        assert_close(&batch[0], &expected);
+    }
+
+    #[test]
+    fn out_of_vocabulary_ignore_ids_are_rejected_at_load() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let error = EmbeddingModel::loaded(&InitRequest {
+            pooling_ignore_token_ids: vec![3, VOCAB.len() as u32],
+            ..init_request(&dir)
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("contains 8 but the vocabulary has 8 tokens"), "{}", error);
+
+        let model = EmbeddingModel::loaded(&InitRequest {
+            pooling_ignore_token_ids: vec![3],
+            ..init_request(&dir)
+        })
+        .unwrap();
+        assert_eq!(model.pooling_ignore_ids, [3].into());
+    }
+
+    #[tokio::test]
+    async fn special_token_positions_mark_cls_and_sep() {
+        let service = service(test_model());
+        let text = "alpha beta gamma delta";
+        let unary = service
+            .token_embed(Request::new(TokenEmbedRequest { text: text.to_string() }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(unary.special_token_positions, [0, 5]);
+        assert_eq!(unary.token_ids[0], 1);
+        assert_eq!(unary.token_ids[5], 2);
+
+        // Absolute positions, each reported only in the chunk that holds it.
+        let chunks = token_chunks(&service, text, 3).await;
+        let positions: Vec<Vec<u32>> = chunks.iter().map(|chunk| chunk.special_token_positions.clone()).collect();
+        assert_eq!(positions, [vec![0], vec![5]]);
+        let chunks = token_chunks(&service, text, 2).await;
+        let positions: Vec<Vec<u32>> = chunks.iter().map(|chunk| chunk.special_token_positions.clone()).collect();
+        assert_eq!(positions, [vec![0], Vec::<u32>::new(), vec![5]]);
+    }
+
+    #[test]
+    fn repeat_short_pads_only_below_the_threshold() {
+        let model = test_model();
+        assert_eq!(model.repeat_short("alpha beta", 3).unwrap().as_deref(), Some("alpha beta alpha beta"));
+        assert_eq!(model.repeat_short("alpha beta", 2).unwrap(), None);
+        assert_eq!(model.repeat_short("alpha beta", 1).unwrap(), None);
+        assert_eq!(model.repeat_short("", 3).unwrap(), None);
+
+        let capped = model.repeat_short("alpha", 100).unwrap().unwrap();
+        assert_eq!(capped.split(' ').count(), MAX_SHORT_INPUT_REPEATS);
+    }
+
+    #[tokio::test]
+    async fn embed_reports_when_it_repeated_a_short_input() {
+        let service = service(test_model());
+        let padded = EmbedRequest {
+            pad_short_below: 3,
+            ..embed_request("alpha")
+        };
+        let response = service.embed(Request::new(padded)).await.unwrap().into_inner();
+        assert!(response.repeated_short_input);
+        assert_close(&response.vector, &test_model().embed("alpha alpha alpha").unwrap());
+
+        let long_enough = EmbedRequest {
+            pad_short_below: 2,
+            ..embed_request("alpha beta")
+        };
+        let response = service.embed(Request::new(long_enough)).await.unwrap().into_inner();
+        assert!(!response.repeated_short_input);
+        assert_close(&response.vector, &test_model().embed("alpha beta").unwrap());
+    }
+
+    #[tokio::test]
+    async fn span_vectors_are_the_mean_of_their_token_rows() {
+        let service = service(test_model());
+        let text = "alpha beta gamma delta";
+        let rows = service
+            .token_embed(Request::new(TokenEmbedRequest { text: text.to_string() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .token_vectors;
+        let request = SpanEmbedRequest {
+            text: text.to_string(),
+            ranges: [(1, 3), (0, 6), (4, 5)]
+                .iter()
+                .map(|&(token_start, token_end)| TokenRange { token_start, token_end })
+                .collect(),
+        };
+        let response = service.span_embed(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(response.token_count, 6);
+        assert_eq!(response.span_vectors.len(), 3);
+
+        for (span, (start, end)) in response.span_vectors.iter().zip([(1, 3), (0, 6), (4, 5)]) {
+            let mut mean = vec![0.0f32; HIDDEN];
+            for row in &rows[start..end] {
+                for (sum, x) in mean.iter_mut().zip(&row.vector) {
+                    *sum += x / (end - start) as f32;
+                }
+            }
+            assert_close(&span.vector, &mean);
+        }
+    }
+
+    /// Records the ids and token type ids of each `forward_pair` call.
+    struct PairRecorder {
+        inner: Box<dyn encoder::Embed>,
+        calls: Arc<std::sync::Mutex<Vec<(Vec<i64>, Vec<i64>)>>>,
+    }
+
+    impl encoder::Embed for PairRecorder {
+        fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+            self.inner.forward(input_ids, attention_mask)
+        }
+
+        fn forward_pair(
+            &self,
+            input_ids: &Tensor,
+            token_type_ids: &Tensor,
+            attention_mask: &Tensor,
+        ) -> candle_core::Result<Tensor> {
+            let call = (input_ids.flatten_all()?.to_vec1()?, token_type_ids.flatten_all()?.to_vec1()?);
+            self.calls.lock().unwrap().push(call);
+            self.inner.forward(input_ids, attention_mask)
+        }
+    }
+
+    #[test]
+    fn structured_input_separates_fields_and_alternates_type_ids() {
+        let mut model = test_model();
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        model.model = Some(Box::new(PairRecorder {
+            inner: model.model.take().unwrap(),
+            calls: calls.clone(),
+        }));
+
+        let (vector, truncated, tokens) = model.embed_structured("alpha | beta gamma | delta", "|").unwrap();
+        assert!(!truncated);
+        assert_eq!(tokens, 8);
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, [1, 4, 2, 5, 6, 2, 7, 2]);
+        assert_eq!(calls[0].1, [0, 0, 0, 1, 1, 1, 0, 0]);
+        assert_close(&vector, &mean_of(&[1, 4, 2, 5, 6, 2, 7, 2]));
+    }
+
+    #[test]
+    fn structured_input_rejects_an_empty_field() {
+        let error = test_model().embed_structured("alpha || beta", "|").unwrap_err();
+        assert!(error.downcast_ref::<InvalidInput>().is_some(), "{}", error);
+        assert!(error.to_string().contains("field 1 is empty"), "{}", error);
+    }
+
+    #[test]
+    fn mixed_precision_picks_f16_off_the_cpu_only() {
+        assert_eq!(model_dtype(None, true, false), DType::F16);
+        assert_eq!(model_dtype(None, false, false), DType::F32);
+        assert_eq!(model_dtype(None, true, true), DType::F32);
+        assert_eq!(model_dtype(Some(DType::BF16), true, false), DType::BF16);
+        assert_eq!(model_dtype(Some(DType::F32), true, false), DType::F32);
+        assert_eq!(model_dtype(Some(DType::F16), false, true), DType::F16);
+    }
+
+    #[test]
+    fn half_precision_hidden_states_pool_in_f32() {
+        let mut model = test_model();
+        let rows: Vec<f32> = (0..VOCAB.len() as u32).flat_map(row).collect();
+        let table = Tensor::from_vec(rows, (VOCAB.len(), HIDDEN), &Device::Cpu).unwrap().to_dtype(DType::F16).unwrap();
+        model.model = Some(Box::new(TableEncoder {
+            table,
+            forwards: Arc::new(AtomicUsize::new(0)),
+            delay: Duration::ZERO,
+        }));
+        model.model_dtype = DType::F16;
+
+        assert_eq!(model.forward(&[1, 4, 5, 2], &[1, 1, 1, 1]).unwrap().dtype(), DType::F32);
+        assert_close(&model.embed("alpha beta").unwrap(), &mean_of(&[1, 4, 5, 2]));
+    }
+
+    fn load_metadata(metadata: &tonic::metadata::MetadataMap) -> (usize, usize) {
+        let read = |key: &str| {
+            let value = metadata.get(key).unwrap_or_else(|| panic!("{} missing", key));
+            value.to_str().unwrap().parse::<usize>().unwrap()
+        };
+        (read("x-sidecar-queue-depth"), read("x-sidecar-in-flight"))
+    }
+
+    #[tokio::test]
+    async fn embed_responses_carry_load_metadata() {
+        let service = service(test_model());
+        let response = service.embed(Request::new(embed_request("alpha"))).await.unwrap();
+        // Sampled after the request's own work, so an otherwise idle service
+        // reports nothing queued or running.
+        assert_eq!(load_metadata(response.metadata()), (0, 0));
+
+        let request = BatchEmbedRequest {
+            texts: vec!["alpha".to_string(), "beta".to_string()],
+            ..BatchEmbedRequest::default()
+        };
+        let response = service.batch_embed(Request::new(request)).await.unwrap();
+        assert_eq!(load_metadata(response.metadata()), (0, 0));
+    }
+
+    #[test]
+    fn route_splits_keys_by_variant_percent() {
+        let mut model = test_model();
+        assert_eq!(model.route("user-1").1, "");
+        model.variant = Some(Box::new(test_model()));
+        model.variant_percent = 30;
+
+        let to_b = (0..10_000).filter(|i| model.route(&format!("user-{}", i)).1 == "b").count();
+        assert!((2_800..=3_200).contains(&to_b), "{} of 10000 keys routed to b", to_b);
+        // Sticky per key, and keyless requests stay on A.
+        assert!((0..100).all(|i| model.route(&format!("user-{}", i)).1 == model.route(&format!("user-{}", i)).1));
+        assert_eq!(model.route("").1, "a");
+
+        model.variant_percent = 0;
+        assert!((0..1_000).all(|i| model.route(&format!("user-{}", i)).1 == "a"));
+        model.variant_percent = 100;
+        assert!((0..1_000).all(|i| model.route(&format!("user-{}", i)).1 == "b"));
+    }
+
+    #[test]
+    fn lsh_codes_are_deterministic_per_seed() {
+        let vector: Vec<f32> = (0..16).map(|i| (i as f32 * 0.7).sin()).collect();
+        let code = lsh_code(&vector, 64, 7).unwrap();
+        assert_eq!(code.len(), 8);
+        assert_eq!(lsh_code(&vector, 64, 7).unwrap(), code);
+        assert_ne!(lsh_code(&vector, 64, 8).unwrap(), code);
+
+        // Signs of projections: scale-invariant, and negation flips every bit.
+        let scaled: Vec<f32> = vector.iter().map(|x| x * 3.0).collect();
+        assert_eq!(lsh_code(&scaled, 64, 7).unwrap(), code);
+        let negated: Vec<f32> = vector.iter().map(|x| -x).collect();
+        let flipped: Vec<u8> = code.iter().map(|byte| !byte).collect();
+        assert_eq!(lsh_code(&negated, 64, 7).unwrap(), flipped);
+
+        assert_eq!(lsh_code(&vector, 12, 7).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn vector_arithmetic_with_opposite_coefficients_is_the_difference() {
+        let term = |text: &str, coefficient: f32| ArithmeticTerm {
+            text: text.to_string(),
+            coefficient,
+        };
+        let service = service(test_model());
+        let request = VectorArithmeticRequest {
+            terms: vec![term("alpha beta", 1.0), term("gamma", -1.0)],
+            normalize: false,
+        };
+        let response = service.vector_arithmetic(Request::new(request)).await.unwrap().into_inner();
+
+        let model = test_model();
+        let (a, b) = (model.embed("alpha beta").unwrap(), model.embed("gamma").unwrap());
+        let difference: Vec<f32> = a.iter().zip(&b).map(|(x, y)| x - y).collect();
+        assert_close(&response.vector, &difference);
+
+        let request = VectorArithmeticRequest {
+            terms: vec![term("alpha beta", 1.0), term("gamma", -1.0)],
+            normalize: true,
+        };
+        let normalized = service.vector_arithmetic(Request::new(request)).await.unwrap().into_inner();
+        let norm = difference.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert_close(&normalized.vector, &difference.iter().map(|x| x / norm).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vector_stats_match_hand_computed_values() {
+        // |x| = 7, ||x|| = 5, and two of four components are zero.
+        let stats = vector_stats(&[3.0, -4.0, 0.0, 0.0], 1e-6);
+        assert_eq!(stats.sparsity, 0.5);
+        assert!((stats.l1_l2_ratio - 1.4).abs() < 1e-6, "{}", stats.l1_l2_ratio);
+        assert_eq!(stats.threshold, 1e-6);
+
+        // The threshold is exclusive.
+        assert_eq!(vector_stats(&[0.5, -0.5, 2.0, 0.0], 0.5).sparsity, 0.25);
+        assert_eq!(vector_stats(&[0.5, -0.5, 2.0, 0.0], 0.6).sparsity, 0.75);
+
+        // The documented extremes: sqrt(dim), 1 and 0.
+        assert!((vector_stats(&[1.0; 4], 0.1).l1_l2_ratio - 2.0).abs() < 1e-6);
+        assert_eq!(vector_stats(&[0.0, -2.5, 0.0], 0.1).l1_l2_ratio, 1.0);
+        let zero = vector_stats(&[0.0; 3], 0.1);
+        assert_eq!((zero.sparsity, zero.l1_l2_ratio), (1.0, 0.0));
+    }
+
+    #[test]
+    fn mips_augmentation_makes_cosine_rank_by_inner_product() {
+        let rank = |scores: Vec<f32>| {
+            let mut order: Vec<usize> = (0..scores.len()).collect();
+            order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+            order
+        };
+        let query = vec![1.0f32, 0.0];
+        let documents = [vec![3.0f32, 3.0], vec![1.0, 0.1], vec![2.0, -1.0], vec![-1.0, 2.0]];
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+
+        let by_inner_product = rank(documents.iter().map(|document| dot(&query, document)).collect());
+        assert_eq!(by_inner_product, [0, 2, 1, 3]);
+        // Plain cosine prefers the short, well-aligned document.
+        let by_cosine = rank(documents.iter().map(|document| cosine_similarity(&query, document)).collect());
+        assert_eq!(by_cosine, [1, 2, 0, 3]);
+
+        let mut augmented_query = query.clone();
+        mips_augment(&mut augmented_query, "query", 5.0).unwrap();
+        let augmented: Vec<Vec<f32>> = documents
+            .iter()
+            .map(|document| {
+                let mut document = document.clone();
+                mips_augment(&mut document, "document", 5.0).unwrap();
+                assert!((dot(&document, &document) - 1.0).abs() < 1e-5);
+                document
+            })
+            .collect();
+        let scores = augmented.iter().map(|document| cosine_similarity(&augmented_query, document)).collect();
+        assert_eq!(rank(scores), by_inner_product);
+        let scores = augmented.iter().map(|document| dot(&augmented_query, document)).collect();
+        assert_eq!(rank(scores), by_inner_product);
+
+        assert!(mips_augment(&mut vec![6.0, 0.0], "document", 5.0).is_err());
+    }
+
+    #[test]
+    fn slow_log_reports_only_at_or_over_the_threshold() {
+        let model = test_model();
+        let slow_log = SlowLog {
+            threshold: Duration::from_millis(100),
+            debug_io: false,
+        };
+        assert_eq!(slow_log.report("embed", Duration::from_millis(99), &model, &["alpha beta"]), None);
+
+        let message = slow_log.report("embed", Duration::from_millis(100), &model, &["alpha beta"]).unwrap();
+        assert!(message.starts_with("Slow embed: 100 ms, 1 text(s), 10 chars, 4 tokens"), "{}", message);
+        assert!(!message.contains("alpha"), "{}", message);
+
+        let debug = SlowLog {
+            debug_io: true,
+            ..slow_log
+        };
+        let message = debug.report("batch_embed", Duration::from_secs(1), &model, &["alpha", "beta"]).unwrap();
+        assert!(message.contains("2 text(s), 9 chars, 6 tokens"), "{}", message);
+        assert!(message.ends_with(r#"input ["alpha", "beta"]"#), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn search_corpus_ranks_by_each_metric() {
+        // The query "alpha" pools to q = mean_of([1, 4, 2]).
+        let q = mean_of(&[1, 4, 2]);
+        let entries = [
+            ("short", q.iter().map(|x| x * 0.5).collect()),
+            ("near", vec![q[0], q[1] + 0.5, q[2], q[3]]),
+            ("long", vec![6.0, 0.0, 0.0, -6.0]),
+            ("skew", vec![q[0] + 1.9, q[1], q[2], q[3]]),
+        ];
+        let mut model = test_model();
+        model.corpus = entries
+            .into_iter()
+            .map(|(id, vector): (&str, Vec<f32>)| CorpusEntry {
+                id: id.to_string(),
+                text: id.to_string(),
+                vector,
+            })
+            .collect();
+        let service = service(model);
+
+        let ranking = |metric: &str| {
+            let request = SearchCorpusRequest {
+                query: "alpha".to_string(),
+                top_k: 0,
+                metric: metric.to_string(),
+            };
+            let service = &service;
+            async move {
+                let hits = service.search_corpus(Request::new(request)).await.unwrap().into_inner().hits;
+                hits.into_iter().map(|hit| hit.id).collect::<Vec<_>>()
+            }
+        };
+        // The longest vector wins on dot product but comes last on cosine;
+        // manhattan and euclidean disagree on skew versus short.
+        assert_eq!(ranking("cosine").await, ["short", "near", "skew", "long"]);
+        assert_eq!(ranking("").await, ["short", "near", "skew", "long"]);
+        assert_eq!(ranking("dot").await, ["long", "skew", "near", "short"]);
+        assert_eq!(ranking("euclidean").await, ["near", "short", "skew", "long"]);
+        assert_eq!(ranking("manhattan").await, ["near", "skew", "short", "long"]);
+
+        let request = SearchCorpusRequest {
+            query: "alpha".to_string(),
+            top_k: 1,
+            metric: "euclidean".to_string(),
+        };
+        let hits = service.search_corpus(Request::new(request)).await.unwrap().into_inner().hits;
+        assert_eq!(hits.len(), 1);
+        assert!((hits[0].score - 0.5).abs() < 1e-5, "{}", hits[0].score);
+    }
+
+    #[test]
+    fn use_pooler_applies_the_checkpoint_pooler_to_cls() {
+        let dir = checkpoint(serde_json::json!({}), true);
+        let cls = |use_pooler: bool| {
+            EmbeddingModel::loaded(&InitRequest {
+                pooling: "cls".to_string(),
+                use_pooler,
+                ..init_request(&dir)
+            })
+            .unwrap()
+        };
+
+        let plain = cls(false);
+        assert!(plain.pooler.is_none());
+        let tokens = plain.encode("alpha beta").unwrap();
+        let hidden_cls = plain.token_embeddings(&tokens).unwrap().remove(0);
+        assert_close(&plain.embed("alpha beta").unwrap(), &hidden_cls);
+
+        let pooled = cls(true);
+        let dense = pooled.pooler.as_ref().expect("pooler loaded");
+        let expected = dense
+            .forward(&Tensor::new(hidden_cls.as_slice(), &Device::Cpu).unwrap().unsqueeze(0).unwrap())
+            .unwrap()
+            .tanh()
+            .unwrap()
+            .squeeze(0)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        let vector = pooled.embed("alpha beta").unwrap();
+        assert_close(&vector, &expected);
+        assert_ne!(vector, hidden_cls);
+    }
+
+    #[test]
+    fn use_pooler_without_pooler_weights_fails_the_load() {
+        let dir = checkpoint(serde_json::json!({}), false);
+        let error = EmbeddingModel::loaded(&InitRequest {
+            pooling: "cls".to_string(),
+            use_pooler: true,
+            ..init_request(&dir)
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("use_pooler set, but the checkpoint has no pooler weights"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn weighted_embed_runs_one_batched_forward() {
+        let (model, forwards) = test_model_with(Duration::ZERO);
+        let response = service(model)
+            .weighted_embed(weighted_request(&["alpha", "beta gamma", "delta"], &[1.0, 1.0, 2.0]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(forwards.load(Ordering::SeqCst), 1);
+        let (alpha, beta_gamma, delta) = (mean_of(&[1, 4, 2]), mean_of(&[1, 5, 6, 2]), mean_of(&[1, 7, 2]));
+        let expected: Vec<f32> =
+            (0..HIDDEN).map(|i| 0.25 * alpha[i] + 0.25 * beta_gamma[i] + 0.5 * delta[i]).collect();
+        assert_close(&response.vector, &expected);
+    }
+
+    #[cfg(feature = "upstream")]
+    #[tokio::test]
+    async fn dim_mismatch_is_not_forwarded_upstream() {
+        let request = EmbedRequest {
+            expected_dim: HIDDEN as i32 + 1,
+            ..embed_request("alpha")
+        };
+        let error = service(test_model()).embed(Request::new(request)).await.unwrap_err();
+        assert!(!should_fall_back(&error));
+        assert!(should_fall_back(&Status::failed_precondition("Model not initialized")));
+        assert!(!should_fall_back(&Status::invalid_argument("text is empty")));
+    }
+}
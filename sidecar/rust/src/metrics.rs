@@ -0,0 +1,121 @@
+//! Prometheus metrics over plain HTTP.
+//!
+//! Set `SIDECAR_METRICS_ADDR` (e.g. `0.0.0.0:9090`) to serve the text
+//! exposition format on `GET /metrics` next to the gRPC listener; unset, no
+//! metrics port is opened. The exporter is hand-rolled: a handful of atomics
+//! and a minimal HTTP/1.1 responder, so it adds no crates.
+//!
+//! | metric                                      | type      | meaning                                   |
+//! |---------------------------------------------|-----------|-------------------------------------------|
+//! | `sidecar_embed_requests_total`              | counter   | Embed calls received                      |
+//! | `sidecar_embed_errors_total`                | counter   | Embed calls that returned an error status |
+//! | `sidecar_embed_inference_duration_seconds`  | histogram | time spent in the model's embed           |
+
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Histogram bucket upper bounds, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    embed_requests: AtomicU64,
+    embed_errors: AtomicU64,
+    /// Non-cumulative count per bucket; the last slot is `+Inf`.
+    inference_buckets: [AtomicU64; BUCKETS.len() + 1],
+    inference_sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn embed_request(&self) {
+        self.embed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn embed_error(&self) {
+        self.embed_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_inference(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(BUCKETS.len());
+        self.inference_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.inference_sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text exposition of every metric.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        };
+        counter(
+            &mut out,
+            "sidecar_embed_requests_total",
+            "Embed calls received.",
+            self.embed_requests.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "sidecar_embed_errors_total",
+            "Embed calls that returned an error status.",
+            self.embed_errors.load(Ordering::Relaxed),
+        );
+
+        let name = "sidecar_embed_inference_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time spent in the model's embed.\n# TYPE {} histogram", name, name);
+        let mut cumulative = 0;
+        for (i, bucket) in self.inference_buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let sum = self.inference_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, cumulative);
+        out
+    }
+}
+
+/// Serve `metrics` on `addr` until the process exits. Only `GET /metrics` is
+/// answered; anything else gets a 404.
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Metrics accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // The request line fits in the first read for any real scraper.
+            let mut request = [0u8; 1024];
+            let read = match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut request)).await {
+                Ok(Ok(read)) => read,
+                _ => return,
+            };
+            let is_metrics = request[..read].starts_with(b"GET /metrics ");
+            let (status, body) = if is_metrics {
+                ("200 OK", metrics.render())
+            } else {
+                ("404 Not Found", "not found\n".to_string())
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
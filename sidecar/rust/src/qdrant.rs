@@ -0,0 +1,146 @@
+//! Qdrant sink for EmbedAndStore.
+//!
+//! Points are upserted through Qdrant's REST API with the blocking `ureq`
+//! client hf-hub already depends on, so enabling the `qdrant` feature adds no
+//! new crates. Set `QDRANT_API_KEY` for instances that require a key.
+
+use std::ops::Range;
+use std::time::Duration;
+
+use serde_json::json;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct QdrantSink {
+    agent: ureq::Agent,
+    points_url: String,
+    api_key: Option<String>,
+}
+
+impl QdrantSink {
+    pub fn new(url: &str, collection: &str) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            points_url: format!("{}/collections/{}/points?wait=true", url.trim_end_matches('/'), collection),
+            api_key: std::env::var("QDRANT_API_KEY").ok().filter(|key| !key.is_empty()),
+        }
+    }
+
+    /// Upsert one batch of `(id, vector, text)` points; the text is stored as
+    /// the point's `text` payload.
+    pub fn upsert(&self, points: &[(u64, &[f32], &str)]) -> anyhow::Result<()> {
+        let body = json!({
+            "points": points
+                .iter()
+                .map(|(id, vector, text)| json!({ "id": id, "vector": vector, "payload": { "text": text } }))
+                .collect::<Vec<_>>(),
+        });
+
+        let mut request = self.agent.put(&self.points_url);
+        if let Some(key) = &self.api_key {
+            request = request.set("api-key", key);
+        }
+        match request.send_json(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                let detail = response.into_string().unwrap_or_default();
+                anyhow::bail!("Qdrant returned HTTP {}: {}", code, detail.trim())
+            }
+            Err(e) => anyhow::bail!("Qdrant request failed: {}", e),
+        }
+    }
+
+    /// Upsert `ids` with their `vectors` and `texts` in batches of
+    /// `batch_size`, carrying on past a failed batch. Returns each batch's
+    /// index range and outcome, in order.
+    pub fn upsert_batches(
+        &self,
+        ids: &[u64],
+        vectors: &[Vec<f32>],
+        texts: &[String],
+        batch_size: usize,
+    ) -> Vec<(Range<usize>, anyhow::Result<()>)> {
+        (0..ids.len())
+            .step_by(batch_size.max(1))
+            .map(|first| {
+                let range = first..(first + batch_size.max(1)).min(ids.len());
+                let points: Vec<(u64, &[f32], &str)> = range
+                    .clone()
+                    .map(|i| (ids[i], vectors[i].as_slice(), texts[i].as_str()))
+                    .collect();
+                let outcome = self.upsert(&points);
+                (range, outcome)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// A one-thread HTTP stub answering each request with the next status in
+    /// `statuses`, closing the connection after every response. Returns its
+    /// base URL and a handle yielding the request bodies it received.
+    fn stub(statuses: Vec<u16>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                let reply = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Length: 4\r\nConnection: close\r\n\r\nstub",
+                    status
+                );
+                reader.get_mut().write_all(reply.as_bytes()).unwrap();
+            }
+            bodies
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn failed_batch_is_reported_and_later_batches_still_run() {
+        let (url, server) = stub(vec![200, 500, 200]);
+        let sink = QdrantSink::new(&url, "docs");
+        let ids = [1, 2, 3, 4, 5];
+        let vectors: Vec<Vec<f32>> = ids.iter().map(|&id| vec![id as f32, 0.5]).collect();
+        let texts: Vec<String> = ids.iter().map(|id| format!("text {}", id)).collect();
+
+        let outcomes = sink.upsert_batches(&ids, &vectors, &texts, 2);
+
+        let ranges: Vec<_> = outcomes.iter().map(|(range, _)| range.clone()).collect();
+        assert_eq!(ranges, vec![0..2, 2..4, 4..5]);
+        assert!(outcomes[0].1.is_ok());
+        let error = outcomes[1].1.as_ref().unwrap_err().to_string();
+        assert!(error.contains("HTTP 500"), "{}", error);
+        assert!(outcomes[2].1.is_ok());
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 3);
+        let points: serde_json::Value = serde_json::from_str(&bodies[1]).unwrap();
+        let sent: Vec<u64> = points["points"].as_array().unwrap().iter().map(|p| p["id"].as_u64().unwrap()).collect();
+        assert_eq!(sent, vec![3, 4]);
+        assert_eq!(points["points"][0]["payload"]["text"], "text 3");
+    }
+}
@@ -0,0 +1,156 @@
+//! Shared-memory output for BatchEmbed (`shm` feature).
+//!
+//! With `shm_output` set, the batch is written as one row-major, native-endian
+//! f32 buffer to a file on a memory-backed filesystem (`/dev/shm` by default,
+//! `SIDECAR_SHM_DIR` to override) and only its handle travels over gRPC. A
+//! co-located client maps the file and reads the floats in place. This only
+//! makes sense for trusted clients on the same host: segments are created
+//! mode 0600, so the client must run as the sidecar's user.
+//!
+//! Lifecycle: ownership of a segment passes to the client with the response,
+//! and the client unlinks it once mapped (a mapping stays valid after unlink).
+//! Segments a client never removes are swept on later writes once older than
+//! `SIDECAR_SHM_TTL_MS` (default 60 s), including leftovers from earlier runs,
+//! so a crashed client leaks memory for at most the TTL.
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::env_millis;
+use crate::sidecar::ShmHandle;
+
+const SEGMENT_PREFIX: &str = "rust-sidecar-";
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+pub struct ShmWriter {
+    dir: PathBuf,
+    ttl: Duration,
+    next: AtomicU64,
+}
+
+impl ShmWriter {
+    pub fn from_env() -> Self {
+        Self {
+            dir: std::env::var_os("SIDECAR_SHM_DIR").map_or_else(|| PathBuf::from("/dev/shm"), PathBuf::from),
+            ttl: env_millis("SIDECAR_SHM_TTL_MS").unwrap_or(DEFAULT_TTL),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// Write `vectors` (each `dim` floats) to a new segment. The file appears
+    /// under its final name only once fully written.
+    pub fn write(&self, vectors: &[Vec<f32>], dim: usize) -> std::io::Result<ShmHandle> {
+        self.sweep();
+
+        let name = format!(
+            "{}{}-{}",
+            SEGMENT_PREFIX,
+            std::process::id(),
+            self.next.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.dir.join(&name);
+        let partial = self.dir.join(format!("{}.partial", name));
+
+        let mut bytes = Vec::with_capacity(vectors.len() * dim * std::mem::size_of::<f32>());
+        for value in vectors.iter().flatten() {
+            bytes.extend_from_slice(&value.to_ne_bytes());
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&partial)?;
+        file.write_all(&bytes)?;
+        std::fs::rename(&partial, &path)?;
+
+        Ok(ShmHandle {
+            path: path.to_string_lossy().into_owned(),
+            offset: 0,
+            len_bytes: bytes.len() as u64,
+            rows: vectors.len() as i32,
+            dim: dim as i32,
+        })
+    }
+
+    /// Remove our segments older than the TTL. Failures are ignored: the
+    /// client may have unlinked the file in the meantime.
+    fn sweep(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        let now = SystemTime::now();
+        for entry in entries.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with(SEGMENT_PREFIX) {
+                continue;
+            }
+            let expired = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > self.ttl);
+            if expired && std::fs::remove_file(entry.path()).is_ok() {
+                tracing::debug!("Removed expired shared-memory segment {}", entry.path().display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A writer over a fresh directory under the system temp dir.
+    fn writer(name: &str) -> ShmWriter {
+        let dir = std::env::temp_dir().join(format!("sidecar-shm-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        ShmWriter {
+            dir,
+            ttl: DEFAULT_TTL,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn written_segment_reads_back_as_the_vectors() {
+        let writer = writer("round-trip");
+        let handle = writer.write(&[vec![1.0, -2.5, 3.0], vec![0.0, 4.25, -6.0]], 3).unwrap();
+        assert_eq!((handle.offset, handle.len_bytes, handle.rows, handle.dim), (0, 24, 2, 3));
+
+        let bytes = std::fs::read(&handle.path).unwrap();
+        assert_eq!(bytes.len() as u64, handle.len_bytes);
+        let floats: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_ne_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(floats, [1.0, -2.5, 3.0, 0.0, 4.25, -6.0]);
+
+        let mode = std::fs::metadata(&handle.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the final name is left behind.
+        assert_eq!(std::fs::read_dir(&writer.dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&writer.dir).unwrap();
+    }
+
+    #[test]
+    fn sweep_removes_only_our_segments_older_than_the_ttl() {
+        let writer = writer("sweep");
+        let stale = writer.dir.join(format!("{}stale", SEGMENT_PREFIX));
+        let foreign = writer.dir.join("someone-elses-file");
+        for path in [&stale, &foreign] {
+            let file = std::fs::File::create(path).unwrap();
+            file.set_modified(SystemTime::now() - 2 * DEFAULT_TTL).unwrap();
+        }
+        let fresh = writer.write(&[vec![1.0]], 1).unwrap();
+
+        // Writing sweeps first; the fresh segment is within the TTL.
+        writer.write(&[vec![2.0]], 1).unwrap();
+        assert!(!stale.exists());
+        assert!(foreign.exists());
+        assert!(std::path::Path::new(&fresh.path).exists());
+        std::fs::remove_dir_all(&writer.dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! HMAC-SHA256 signatures over Embed output.
+//!
+//! Set `SIDECAR_SIGNING_KEY` to have Embed, WeightedEmbed and VectorArithmetic
+//! responses carry `signature`, the HMAC-SHA256 under that key of the
+//! vector's little-endian f32 bytes (as in `raw_vector`) followed by the UTF-8
+//! `model_fingerprint`. Anyone holding the key can then check that a stored
+//! vector came from this server's model unaltered. Unset, nothing is signed.
+//!
+//! HMAC is a few lines over the `sha2` hf-hub already pulls in, so it adds no
+//! crates.
+
+use sha2::{Digest, Sha256};
+
+/// SHA-256 block size; keys are padded (or first hashed) to this length.
+const BLOCK: usize = 64;
+
+pub struct Signer {
+    key: [u8; BLOCK],
+}
+
+impl Signer {
+    pub fn from_env() -> Option<Self> {
+        let key = std::env::var("SIDECAR_SIGNING_KEY").ok().filter(|key| !key.is_empty())?;
+        Some(Self::new(key.as_bytes()))
+    }
+
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; BLOCK];
+        if key.len() > BLOCK {
+            block[..32].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        Self { key: block }
+    }
+
+    /// HMAC-SHA256 (RFC 2104) of `vector_bytes || fingerprint`.
+    pub fn sign(&self, vector_bytes: &[u8], fingerprint: &str) -> Vec<u8> {
+        let pad = |byte: u8| self.key.map(|k| k ^ byte);
+        let inner = Sha256::new()
+            .chain_update(pad(0x36))
+            .chain_update(vector_bytes)
+            .chain_update(fingerprint.as_bytes())
+            .finalize();
+        Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// HMAC-SHA256 of `data` under `key`. `sign` appends the fingerprint to
+    /// the vector bytes, so an empty fingerprint signs `data` alone.
+    fn hmac(key: &[u8], data: &[u8]) -> String {
+        hex(&Signer::new(key).sign(data, ""))
+    }
+
+    // Test cases 1-4, 6 and 7 of RFC 4231, section 4. Case 5 covers output
+    // truncation, which signatures don't use.
+    #[test]
+    fn rfc4231_case_1() {
+        assert_eq!(
+            hmac(&[0x0b; 20], b"Hi There"),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+    }
+
+    #[test]
+    fn rfc4231_case_2() {
+        assert_eq!(
+            hmac(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn rfc4231_case_3() {
+        assert_eq!(
+            hmac(&[0xaa; 20], &[0xdd; 50]),
+            "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"
+        );
+    }
+
+    #[test]
+    fn rfc4231_case_4() {
+        let key: Vec<u8> = (0x01..=0x19).collect();
+        assert_eq!(
+            hmac(&key, &[0xcd; 50]),
+            "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"
+        );
+    }
+
+    #[test]
+    fn rfc4231_case_6_key_longer_than_block() {
+        assert_eq!(
+            hmac(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn rfc4231_case_7_key_and_data_longer_than_block() {
+        let data = b"This is a test using a larger than block-size key and a larger than block-size data. \
+                     The key needs to be hashed before being used by the HMAC algorithm.";
+        assert_eq!(
+            hmac(&[0xaa; 131], data),
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2"
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_signed_after_the_vector_bytes() {
+        let signer = Signer::new(b"key");
+        assert_eq!(signer.sign(b"vector", "fp"), signer.sign(b"vectorfp", ""));
+        assert_ne!(signer.sign(b"vector", "fp"), signer.sign(b"vector", "other"));
+    }
+}
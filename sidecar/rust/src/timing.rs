@@ -0,0 +1,89 @@
+//! Per-phase timing for `EmbedRequest.timing`.
+//!
+//! The model's tokenization, forward pass and pooling report their durations
+//! through [`time`], which records only while a [`measure`] call is running
+//! on the same thread. Embed runs its model work on a single blocking-pool
+//! thread, so a thread-local is enough, and requests without timing pay one
+//! thread-local check per phase.
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Tokenize,
+    Forward,
+    Pool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Phases {
+    pub tokenize: Duration,
+    pub forward: Duration,
+    pub pool: Duration,
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Option<Phases>> = const { RefCell::new(None) };
+}
+
+/// Run `work`, collecting the phases it reports when `enabled`.
+pub fn measure<T>(enabled: bool, work: impl FnOnce() -> T) -> (T, Phases) {
+    if !enabled {
+        return (work(), Phases::default());
+    }
+    ACTIVE.with(|active| *active.borrow_mut() = Some(Phases::default()));
+    let value = work();
+    let phases = ACTIVE.with(|active| active.borrow_mut().take()).unwrap_or_default();
+    (value, phases)
+}
+
+/// Run `work`, adding its duration to `phase` if a measurement is active.
+pub fn time<T>(phase: Phase, work: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let value = work();
+    ACTIVE.with(|active| {
+        if let Some(phases) = active.borrow_mut().as_mut() {
+            let slot = match phase {
+                Phase::Tokenize => &mut phases.tokenize,
+                Phase::Forward => &mut phases.forward,
+                Phase::Pool => &mut phases.pool,
+            };
+            *slot += started.elapsed();
+        }
+    });
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleep(ms: u64) {
+        std::thread::sleep(Duration::from_millis(ms));
+    }
+
+    #[test]
+    fn measure_collects_each_phase() {
+        let (value, phases) = measure(true, || {
+            time(Phase::Tokenize, || sleep(5));
+            time(Phase::Forward, || sleep(20));
+            time(Phase::Forward, || sleep(5));
+            time(Phase::Pool, || 7)
+        });
+        assert_eq!(value, 7);
+        assert!(phases.tokenize >= Duration::from_millis(5));
+        assert!(phases.forward >= Duration::from_millis(25));
+        assert!(phases.pool < phases.tokenize);
+    }
+
+    #[test]
+    fn nothing_is_recorded_unless_enabled() {
+        let (_, phases) = measure(false, || time(Phase::Forward, || sleep(5)));
+        assert_eq!(phases.forward, Duration::ZERO);
+        // Nor does a phase outside any measurement leak into the next one.
+        time(Phase::Forward, || sleep(5));
+        let (_, phases) = measure(true, || ());
+        assert_eq!(phases.forward, Duration::ZERO);
+    }
+}
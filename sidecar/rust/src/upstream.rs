@@ -0,0 +1,178 @@
+//! Upstream embedding backend for proxy/fallback deployments.
+//!
+//! When `SIDECAR_UPSTREAM_ADDR` is set, requests the local model cannot serve
+//! (not loaded, inference failure) are forwarded to another sidecar speaking
+//! the same `LLMService` protocol. The channel reconnects on its own and
+//! balances across every listed address; calls are retried a bounded number
+//! of times, and a circuit breaker stops hammering an upstream that keeps
+//! failing until a background health probe sees it recover.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+use crate::sidecar::llm_service_client::LlmServiceClient;
+use crate::sidecar::{BatchEmbedRequest, BatchEmbedResponse, EmbedRequest, EmbedResponse, HealthRequest};
+
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Opens after `threshold` consecutive failures and rejects calls until
+/// `cooldown` passes or a health probe succeeds.
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    fn allows_request(&self) -> bool {
+        self.open_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= self.threshold {
+            if self.open_until.is_none() {
+                tracing::warn!(
+                    "Upstream circuit opened after {} consecutive failures",
+                    self.consecutive_failures
+                );
+            }
+            self.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+pub struct UpstreamClient {
+    client: LlmServiceClient<Channel>,
+    retries: u32,
+    breaker: Mutex<CircuitBreaker>,
+}
+
+impl UpstreamClient {
+    /// Build the client from `SIDECAR_UPSTREAM_*` variables; `Ok(None)` when no
+    /// upstream is configured.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(addrs) = std::env::var("SIDECAR_UPSTREAM_ADDR") else {
+            return Ok(None);
+        };
+
+        let endpoints = addrs
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| {
+                Endpoint::from_shared(addr.to_string())
+                    .map(|endpoint| endpoint.connect_timeout(Duration::from_secs(5)).timeout(REQUEST_TIMEOUT))
+                    .map_err(|e| anyhow::anyhow!("Invalid SIDECAR_UPSTREAM_ADDR entry {:?}: {}", addr, e))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if endpoints.is_empty() {
+            anyhow::bail!("SIDECAR_UPSTREAM_ADDR is set but lists no addresses");
+        }
+        tracing::info!("Upstream fallback enabled for {}", addrs);
+
+        let env_u32 = |name: &str, default: u32| {
+            std::env::var(name).ok().and_then(|v| v.trim().parse().ok()).unwrap_or(default)
+        };
+        let cooldown = std::env::var("SIDECAR_UPSTREAM_BREAKER_COOLDOWN_MS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .map_or(DEFAULT_BREAKER_COOLDOWN, Duration::from_millis);
+
+        Ok(Some(Self {
+            client: LlmServiceClient::new(Channel::balance_list(endpoints.into_iter())),
+            retries: env_u32("SIDECAR_UPSTREAM_RETRIES", DEFAULT_RETRIES),
+            breaker: Mutex::new(CircuitBreaker {
+                consecutive_failures: 0,
+                open_until: None,
+                threshold: env_u32("SIDECAR_UPSTREAM_BREAKER_THRESHOLD", DEFAULT_BREAKER_THRESHOLD).max(1),
+                cooldown,
+            }),
+        }))
+    }
+
+    pub async fn embed(&self, request: EmbedRequest) -> Result<EmbedResponse, Status> {
+        self.call(|mut client| {
+            let request = request.clone();
+            async move { client.embed(request).await }
+        })
+        .await
+    }
+
+    pub async fn batch_embed(&self, request: BatchEmbedRequest) -> Result<BatchEmbedResponse, Status> {
+        self.call(|mut client| {
+            let request = request.clone();
+            async move { client.batch_embed(request).await }
+        })
+        .await
+    }
+
+    /// Probe upstream health periodically so an open circuit closes as soon
+    /// as the upstream recovers instead of waiting out the cooldown.
+    pub async fn run_health_checks(&self) {
+        let mut interval = tokio::time::interval(HEALTH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let healthy = matches!(
+                self.client.clone().health(HealthRequest::default()).await,
+                Ok(response) if response.get_ref().healthy
+            );
+            let mut breaker = self.breaker.lock().unwrap();
+            if healthy && !breaker.allows_request() {
+                tracing::info!("Upstream healthy again, closing circuit");
+                breaker.record_success();
+            } else if !healthy {
+                breaker.record_failure();
+            }
+        }
+    }
+
+    async fn call<T, F, Fut>(&self, send: F) -> Result<T, Status>
+    where
+        F: Fn(LlmServiceClient<Channel>) -> Fut,
+        Fut: std::future::Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        if !self.breaker.lock().unwrap().allows_request() {
+            return Err(Status::unavailable("Upstream circuit is open"));
+        }
+
+        let mut attempt = 0;
+        loop {
+            match send(self.client.clone()).await {
+                Ok(response) => {
+                    self.breaker.lock().unwrap().record_success();
+                    return Ok(response.into_inner());
+                }
+                Err(status) if is_retryable(&status) && attempt < self.retries => {
+                    attempt += 1;
+                    tracing::debug!("Upstream call failed ({}), retry {}/{}", status, attempt, self.retries);
+                    tokio::time::sleep(Duration::from_millis(100 * 2u64.pow(attempt))).await;
+                }
+                Err(status) => {
+                    if is_retryable(&status) {
+                        self.breaker.lock().unwrap().record_failure();
+                    }
+                    return Err(status);
+                }
+            }
+        }
+    }
+}
+
+/// Transport-level failures worth retrying; application errors are not.
+fn is_retryable(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded | Code::Unknown)
+}